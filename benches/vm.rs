@@ -0,0 +1,57 @@
+// Times the vm on a few small programs: `cargo bench`. Nothing fancy, each
+// program is compiled once and run a number of times, the fastest run counts.
+use relax::syntax::scanner::Scanner;
+use relax::syntax::parser::Parser;
+use relax::bytecode::compiler::{compile,Bytecode};
+use relax::bytecode::vm::Vm;
+use relax::module::Loader;
+use std::io;
+use std::time::{Duration,Instant};
+
+const RUNS: u32 = 10;
+
+fn bytecode(source: &str) -> Bytecode {
+    let mut scanner = Scanner::new(source.to_string());
+    if let Err(e) = scanner.scan() {
+        panic!("{:?}",e);
+    }
+    let (statements,errors) = Parser::new(scanner.tokens).parse();
+    assert!(errors.is_empty(),"{:?}",errors);
+    match compile(statements,Loader::default()) {
+        Ok(chunk) => chunk.to_bytecode(),
+        Err(e) => panic!("{:?}",e)
+    }
+}
+
+fn bench(name: &str,source: &str) {
+    let b = bytecode(source);
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let mut vm = Vm::with_output(Box::new(io::sink()));
+        vm.load(&b);
+        let start = Instant::now();
+        if let Err(e) = vm.interpret() {
+            panic!("{} failed: {}",name,e);
+        }
+        best = best.min(start.elapsed());
+    }
+    println!("{:<12} {:>10.3} ms",name,best.as_secs_f64() * 1000.0);
+}
+
+// A long chain of additions, which nests as deep as it's long.
+fn deep_expression(terms: usize) -> String {
+    let sum = vec!["1"; terms].join(" + ");
+    let mut source = String::from("var s;\n");
+    for _ in 0..200 {
+        source.push_str(&format!("s = {};\n",sum));
+    }
+    source
+}
+
+fn main() {
+    bench("loop","var i = 0; var sum = 0; while (i < 1000000) { sum = sum + i; i = i + 1; } print sum;");
+    bench("calls","fun add(a, b) { return a + b; } var i = 0; while (i < 200000) { i = add(i, 1); } print i;");
+    bench("fields","class P { init(x) { this.x = x; } } var p = P(0); while (p.x < 200000) { p.x = p.x + 1; } print p.x;");
+    bench("closures","fun counter() { var n = 0; return fun () { n = n + 1; return n; }; } var c = counter(); var i = 0; while (i < 200000) { i = c(); } print i;");
+    bench("deep",&deep_expression(500));
+}
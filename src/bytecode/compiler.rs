@@ -2,8 +2,13 @@ use super::expr::*;
 use super::token_type::*;
 use super::lox_type::*;
 use super::statements::*;
+use super::token::{Token,Span};
+use super::module::{Loader,Import};
+use super::err::CompileError;
+use super::resolver::{Resolver,Resolution,Access};
+use super::lines::*;
+use std::collections::{HashMap,HashSet};
 use std::rc::Rc;
-use std::collections::HashMap;
 
 trait VecPutAndGetIndex {
     type Item;
@@ -28,89 +33,324 @@ pub enum Operation {
     Subtract, // 4
     Multiply, // 5
     Divide, // 6
-    Negate, // 7
-    And, // 8
+    Negate, // 7 numbers only
+    And, // 8 jumps over the right operand, see below
     Or, // 9
     Equals, // 10
     Greater, // 11
     GreaterEqual, // 12
     Lesser, // 13
     LesserEqual, // 14
-    Var, // 15 declare a variable
-    Assign, // 16 Assign a variable
-    Pop, // 17 Pop from the locals stack,
-    Get, // 18 Get value from a variable
-    JumpBackIfFalse, // 19
-    JumpBackIfTrue, // 20
+    DefineGlobal, // 15 declare a global variable
+    SetGlobal, // 16 assign a global variable
+    Pop, // 17 drop values off the stack
+    GetGlobal, // 18 Get value from a global variable
+    Jump, // 19
+    JumpBack, // 20
     JumpIfTrue, // 21
     JumpIfFalse, // 22
     NotEquals, // 23,
-    Operand(u64) // internally it's an u64, but it can range from u8 up to 64
+    Call, // 24
+    Closure, // 25
+    GetUpvalue, // 26
+    SetUpvalue, // 27
+    Class, // 28
+    Inherit, // 29
+    Method, // 30
+    GetProperty, // 31
+    SetProperty, // 32
+    GetSuper, // 33
+    Print, // 34
+    Import, // 35
+    ImportModule, // 36
+    Local, // 37 declare a local variable in the next stack slot
+    GetLocal, // 38
+    SetLocal, // 39
+    ImportNames, // 40
+    LongDefineGlobal, // 41 the long variants take a two byte operand instead of one
+    LongSetGlobal, // 42
+    LongGetGlobal, // 43
+    LongGetLocal, // 44
+    LongSetLocal, // 45
+    LongJumpIfTrue, // 46
+    LongJumpIfFalse, // 47
+    LongJump, // 48
+    LongJumpBack, // 49
+    LongAnd, // 50
+    LongOr, // 51
+    Not, // 52 by truthiness
+    Byte(u8), // operands, each op always takes the same widths
+    Short(u16), // written little endian
+    Word(u32),
+    Line(i32), // marks the source line of the ops after it, doesn't get encoded
+    Break, // marks the jump after it as leaving the innermost loop, not encoded either
+    Continue // same, for going on to the loop's next iteration
 }
 
 /*
-RETURN
-CONSTANT <OPERAND>
-LongConstant <OPERAND>
-Add <OPERATION> <OPERATION>
+Ops are postfix: the values an op works on are pushed onto the stack by the
+ops before it, it pops them and pushes its result. Operands follow the op, each
+op always takes the same widths, <U8> is one byte, <U16> two and <U32> four,
+little endian. Long variants take a <U16> where the op takes a <U8>, or a <U32>
+where it takes a <U16>.
+
+RETURN // returns the value on top of the stack from the current function
+CONSTANT <U8>
+LONG_CONSTANT <U16>
+ADD // pops b, then a, pushes a + b
 ...
-NOT <OPERATION>
+NEGATE // pops a number, pushes its negation
+NOT // pops a value, pushes whether it's falsey
 ...
-VAR <OPERAND> // id of variable, constants list
-ASSIGN <OPERAND> <OP> // index of identifier, value to assign
+DEFINE_GLOBAL <U8> // name of the variable in the constants list, pops its value
+SET_GLOBAL <U8> // name of the variable, assigns the value on top, leaving it there
+GET_GLOBAL <U8> // name of the variable
+LOCAL // pushes nil as a new local, its slot is the number of locals before it,
+    // a local with an initializer is just its value being left on the stack
+SET_LOCAL <U8> // slot, counted from the start of the call's locals
+GET_LOCAL <U8>
+POP <U8> // how many values to drop, locals at the end of a block or an
+    // expression statement's value
+JUMP <U16> // jumps forward by the offset, in bytes counted from after it
+JUMP_BACK <U16> // jumps back by the offset, counted from after it too
+JUMP_IF_FALSE <U16> // pops the condition, jumps forward by the offset if it's false
+JUMP_IF_TRUE <U16> // same, if it's true
+AND <U16> // if the value on top is falsey jumps forward by the offset, over the
+    // right operand, leaving it as the result. Otherwise pops it
+OR <U16> // same, if it's truthy
+CALL <U8> // argument count, the callee is below the arguments and the
+    // result replaces them both
+CLOSURE <U16> (<U8> <U16>)* // function constant, then per upvalue:
+    // 1 and the variable's slot to capture a local of the enclosing function,
+    // 0 and an upvalue index to share one of the enclosing function's upvalues
+GET_UPVALUE <U8>
+SET_UPVALUE <U8>
+CLASS <U16> // name of the class
+INHERIT // pops the superclass, then the class
+METHOD <U16> // name of the method, pops the closure, then the class
+GET_PROPERTY <U16> // name of the property, pops the instance
+SET_PROPERTY <U16> // name of the property, pops the value, then the instance
+GET_SUPER <U16> // name of the method, pops the superclass, then the instance
+PRINT // pops the value to print
+IMPORT <U16> // the module's function, runs it unless it has run already
+IMPORT_MODULE <U16> <U16> <U16>* // the module's function, how many names it
+    // exports and the names, pushes the module
+IMPORT_NAMES // pops a module, copies what it exports into the importer's globals
 */
 
 impl Operation {
-    fn to_bytes(&self) -> Vec<u8> {
+    pub fn from_byte(b: u8) -> Option<Operation> {
+        Some(match b {
+            0 => Operation::Return,
+            1 => Operation::Constant,
+            2 => Operation::LongConstant,
+            3 => Operation::Add,
+            4 => Operation::Subtract,
+            5 => Operation::Multiply,
+            6 => Operation::Divide,
+            7 => Operation::Negate,
+            8 => Operation::And,
+            9 => Operation::Or,
+            10 => Operation::Equals,
+            11 => Operation::Greater,
+            12 => Operation::GreaterEqual,
+            13 => Operation::Lesser,
+            14 => Operation::LesserEqual,
+            15 => Operation::DefineGlobal,
+            16 => Operation::SetGlobal,
+            17 => Operation::Pop,
+            18 => Operation::GetGlobal,
+            19 => Operation::Jump,
+            20 => Operation::JumpBack,
+            21 => Operation::JumpIfTrue,
+            22 => Operation::JumpIfFalse,
+            23 => Operation::NotEquals,
+            24 => Operation::Call,
+            25 => Operation::Closure,
+            26 => Operation::GetUpvalue,
+            27 => Operation::SetUpvalue,
+            28 => Operation::Class,
+            29 => Operation::Inherit,
+            30 => Operation::Method,
+            31 => Operation::GetProperty,
+            32 => Operation::SetProperty,
+            33 => Operation::GetSuper,
+            34 => Operation::Print,
+            35 => Operation::Import,
+            36 => Operation::ImportModule,
+            37 => Operation::Local,
+            38 => Operation::GetLocal,
+            39 => Operation::SetLocal,
+            40 => Operation::ImportNames,
+            41 => Operation::LongDefineGlobal,
+            42 => Operation::LongSetGlobal,
+            43 => Operation::LongGetGlobal,
+            44 => Operation::LongGetLocal,
+            45 => Operation::LongSetLocal,
+            46 => Operation::LongJumpIfTrue,
+            47 => Operation::LongJumpIfFalse,
+            48 => Operation::LongJump,
+            49 => Operation::LongJumpBack,
+            50 => Operation::LongAnd,
+            51 => Operation::LongOr,
+            52 => Operation::Not,
+            _ => return None
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
         match self {
-            &Operation::Return => bytes.push(0),
-            &Operation::Constant => bytes.push(1),
-            &Operation::LongConstant => bytes.push(2),
-            &Operation::Add => bytes.push(3),
-            &Operation::Subtract => bytes.push(4),
-            &Operation::Multiply => bytes.push(5),
-            &Operation::Divide => bytes.push(6),
-            &Operation::Negate => bytes.push(7),
-            &Operation::And => bytes.push(8),
-            &Operation::Or => bytes.push(9),
-            &Operation::Equals => bytes.push(10),
-            &Operation::Greater => bytes.push(11),
-            &Operation::GreaterEqual => bytes.push(12),
-            &Operation::Lesser => bytes.push(13),
-            &Operation::LesserEqual => bytes.push(14),
-            &Operation::Var => bytes.push(15),
-            &Operation::Assign => bytes.push(16),
-            &Operation::Pop => bytes.push(17),
-            &Operation::Get => bytes.push(18),
-            &Operation::JumpBackIfTrue => bytes.push(19),
-            &Operation::JumpBackIfFalse => bytes.push(20),
-            &Operation::JumpIfTrue => bytes.push(21),
-            &Operation::JumpIfFalse => bytes.push(22),
-            &Operation::NotEquals => bytes.push(23),
-            &Operation::Operand(ref a) => {
-                if *a < u8::MAX as u64 {
-                    bytes.extend_from_slice(&(*a as u8).to_le_bytes())
-                } else if *a < u16::MAX as u64 {
-                    bytes.extend_from_slice(&(*a as u16).to_le_bytes())
-                } else if *a < u32::MAX as u64 {
-                    bytes.extend_from_slice(&(*a as u32).to_le_bytes())
-                } else {
-                    bytes.extend_from_slice(&(*a as u64).to_le_bytes())
-                }
-            }
+            Operation::Return => bytes.push(0),
+            Operation::Constant => bytes.push(1),
+            Operation::LongConstant => bytes.push(2),
+            Operation::Add => bytes.push(3),
+            Operation::Subtract => bytes.push(4),
+            Operation::Multiply => bytes.push(5),
+            Operation::Divide => bytes.push(6),
+            Operation::Negate => bytes.push(7),
+            Operation::And => bytes.push(8),
+            Operation::Or => bytes.push(9),
+            Operation::Equals => bytes.push(10),
+            Operation::Greater => bytes.push(11),
+            Operation::GreaterEqual => bytes.push(12),
+            Operation::Lesser => bytes.push(13),
+            Operation::LesserEqual => bytes.push(14),
+            Operation::DefineGlobal => bytes.push(15),
+            Operation::SetGlobal => bytes.push(16),
+            Operation::Pop => bytes.push(17),
+            Operation::GetGlobal => bytes.push(18),
+            Operation::Jump => bytes.push(19),
+            Operation::JumpBack => bytes.push(20),
+            Operation::JumpIfTrue => bytes.push(21),
+            Operation::JumpIfFalse => bytes.push(22),
+            Operation::NotEquals => bytes.push(23),
+            Operation::Call => bytes.push(24),
+            Operation::Closure => bytes.push(25),
+            Operation::GetUpvalue => bytes.push(26),
+            Operation::SetUpvalue => bytes.push(27),
+            Operation::Class => bytes.push(28),
+            Operation::Inherit => bytes.push(29),
+            Operation::Method => bytes.push(30),
+            Operation::GetProperty => bytes.push(31),
+            Operation::SetProperty => bytes.push(32),
+            Operation::GetSuper => bytes.push(33),
+            Operation::Print => bytes.push(34),
+            Operation::Import => bytes.push(35),
+            Operation::ImportModule => bytes.push(36),
+            Operation::Local => bytes.push(37),
+            Operation::GetLocal => bytes.push(38),
+            Operation::SetLocal => bytes.push(39),
+            Operation::ImportNames => bytes.push(40),
+            Operation::Line(_) | Operation::Break | Operation::Continue => {},
+            Operation::LongDefineGlobal => bytes.push(41),
+            Operation::LongSetGlobal => bytes.push(42),
+            Operation::LongGetGlobal => bytes.push(43),
+            Operation::LongGetLocal => bytes.push(44),
+            Operation::LongSetLocal => bytes.push(45),
+            Operation::LongJumpIfTrue => bytes.push(46),
+            Operation::LongJumpIfFalse => bytes.push(47),
+            Operation::LongJump => bytes.push(48),
+            Operation::LongJumpBack => bytes.push(49),
+            Operation::LongAnd => bytes.push(50),
+            Operation::LongOr => bytes.push(51),
+            Operation::Not => bytes.push(52),
+            Operation::Byte(b) => bytes.push(b),
+            Operation::Short(s) => bytes.extend_from_slice(&s.to_le_bytes()),
+            Operation::Word(w) => bytes.extend_from_slice(&w.to_le_bytes())
         }
         bytes
     }
 }
 
+impl Operation {
+    // How many bytes it's encoded as, what `to_bytes` gives without building it.
+    fn width(self) -> usize {
+        match self {
+            Operation::Line(_) | Operation::Break | Operation::Continue => 0,
+            Operation::Short(_) => 2,
+            Operation::Word(_) => 4,
+            _ => 1
+        }
+    }
+}
+
+fn encoded_len(ops: &[Operation]) -> usize {
+    ops.iter().map(|op| op.width()).sum()
+}
+
+// Constants that are the same value share an index. Numbers are told apart by
+// their bits, functions by which function they are.
+#[derive(Debug,PartialEq,Eq,Hash)]
+enum ConstantKey {
+    String(String),
+    Number(u64),
+    Boolean(bool),
+    Nil,
+    Function(*const Function)
+}
+
+impl ConstantKey {
+    fn of(v: &LoxType) -> Option<ConstantKey> {
+        Some(match v {
+            &LoxType::String(ref s) => ConstantKey::String(s.clone()),
+            &LoxType::Number(n) => ConstantKey::Number(n.to_bits()),
+            &LoxType::Boolean(b) => ConstantKey::Boolean(b),
+            &LoxType::Nil => ConstantKey::Nil,
+            &LoxType::Function(ref f) => ConstantKey::Function(Rc::as_ptr(f)),
+            _ => return None
+        })
+    }
+}
+
+// Ops that take a one byte operand have a long variant for bigger ones.
+fn with_operand(short: Operation,long: Operation,operand: usize) -> Vec<Operation> {
+    if operand <= u8::MAX as usize {
+        vec![short,Operation::Byte(operand as u8)]
+    } else {
+        vec![long,Operation::Short(operand as u16)]
+    }
+}
+
+// Where errors about the code generated for a statement, rather than one of its
+// tokens, point: the first line it was compiled from.
+fn line_span(ops: &[Operation]) -> Span {
+    let line = ops.iter().find_map(|op| match op {
+        &Operation::Line(l) => Some(l),
+        _ => None
+    }).unwrap_or(1);
+    Span { start: 0, end: 0, line, column: 1 }
+}
+
+// Constants are indexed with at most two bytes.
+const CONSTANTS_MAX: usize = u16::MAX as usize + 1;
+
+// What a chunk compiles down to: the encoded ops, the constants they refer to
+// and the source line each byte came from.
+#[derive(Debug,Clone,Default)]
+pub struct Bytecode {
+    pub code: Vec<u8>,
+    pub constants: Vec<LoxType>,
+    pub lines: LineTable
+}
 
-#[derive(Debug)]
+// Which variable a name refers to is up to the resolver, the compiler only
+// keeps count of the locals of the function it's compiling, by the depth of
+// the scope each was declared in. A local's slot is its index.
+#[derive(Debug,Default)]
 pub struct Chunk {
     pub ops: Vec<Operation>,
     pub constants: Vec<LoxType>,
-    var_count: u8,
-    curr_depth: u8
+    constant_indexes: HashMap<ConstantKey,usize>,
+    curr_depth: usize,
+    locals: Vec<usize>, // the depth of each local
+    loops: Vec<usize>, // how many locals were declared outside each enclosing loop's body
+    globals: HashSet<String>, // declared at the top level
+    global_uses: Vec<(Token,bool)>, // names used as globals, and whether inside a function
+    resolution: Rc<Resolution>,
+    function: bool, // compiling a function's body rather than top-level code
+    file: Option<Rc<str>>, // the module being compiled, None for the main program
+    pub loader: Loader
 }
 
 impl Chunk {
@@ -118,29 +358,109 @@ impl Chunk {
         Chunk {
             ops: Vec::new(),
             constants: Vec::new(),
-            var_count: 0,
-            curr_depth: 0
+            constant_indexes: HashMap::new(),
+            curr_depth: 0,
+            locals: Vec::new(),
+            loops: Vec::new(),
+            globals: HashSet::new(),
+            global_uses: Vec::new(),
+            resolution: Rc::new(Resolution::default()),
+            function: false,
+            file: None,
+            loader: Loader::default()
+        }
+    }
+
+    pub fn with_loader(loader: Loader) -> Chunk {
+        Chunk {
+            loader,
+            ..Chunk::new()
+        }
+    }
+
+    // Leaves the innermost scope, returning how many locals it declared.
+    fn end_scope(&mut self) -> usize {
+        let depth = self.curr_depth;
+        let count = self.locals.len();
+        self.locals.retain(|&d| d < depth);
+        self.curr_depth -= 1;
+        count - self.locals.len()
+    }
+
+    // Variables declared inside a block take the next slot, at the top level
+    // they're globals.
+    fn declare(&mut self,name: &str) -> Access {
+        if self.curr_depth > 0 {
+            Access::Local(self.locals.put_and_get_index(self.curr_depth) as u16)
+        } else {
+            self.globals.insert(name.to_string());
+            Access::Global
+        }
+    }
+
+    // Brings a declared variable into being, after its value. A local is the
+    // value left on the stack in its slot.
+    fn encode_define(&mut self,access: Access,name: &str) -> Vec<Operation> {
+        match access {
+            Access::Local(_) => Vec::new(),
+            _ => {
+                let idx = self.set_const(LoxType::String(name.to_string()));
+                with_operand(Operation::DefineGlobal,Operation::LongDefineGlobal,idx)
+            }
+        }
+    }
+
+    // Where the resolver found a name to refer to. Globals named by the
+    // program have to be declared somewhere in it.
+    fn access(&mut self,t: &Token) -> Access {
+        let access = self.resolution.access(t);
+        if access == Access::Global {
+            self.global_uses.push((t.clone(),self.function));
+        }
+        access
+    }
+
+    // Globals are bound late, so whether one is declared is only known once
+    // the whole program has been compiled. Unless it's only used by functions,
+    // which may be called after more of the program has been compiled.
+    fn check_globals(&mut self,in_functions: bool) {
+        for (t,in_function) in std::mem::take(&mut self.global_uses) {
+            if (in_functions || !in_function) && !self.globals.contains(&t.lexeme) {
+                self.loader.error(format!("Undefined variable '{}'",t.lexeme),t.span);
+            }
         }
     }
 
     pub fn op_const(&mut self,v: LoxType) -> Vec<Operation> {
-        let mut ops = Vec::new();
         let idx = self.set_const(v);
-        if idx < 256 {
-            ops.push(Operation::Constant);
-        } else {
-            ops.push(Operation::LongConstant);
+        with_operand(Operation::Constant,Operation::LongConstant,idx)
+    }
+
+    // A constant for ops that always take a two byte operand.
+    fn short_const(&mut self,v: LoxType) -> Operation {
+        Operation::Short(self.set_const(v) as u16)
+    }
+
+    // Past the limit the indexes wrap, the code is never run since it's an
+    // error to have that many.
+    fn check_constants(&mut self,span: Span) -> bool {
+        if self.constants.len() > CONSTANTS_MAX {
+            self.loader.error("Too many constants in one chunk".to_string(),span);
+            return false;
         }
-        ops.push(Operation::Operand(idx as u64));
-        ops
+        true
     }
 
     pub fn set_const(&mut self,v: LoxType) -> usize {
-        if let Some(_idx) = self.constants.iter().position(|x| x == &v) {
-            _idx
-        } else {
-            self.constants.put_and_get_index(v)
+        let key = ConstantKey::of(&v);
+        if let Some(&idx) = key.as_ref().and_then(|k| self.constant_indexes.get(k)) {
+            return idx;
         }
+        let idx = self.constants.put_and_get_index(v);
+        if let Some(k) = key {
+            self.constant_indexes.insert(k,idx);
+        }
+        idx
     }
 
     pub fn encode_expr(&mut self, expr: &Expr) -> Vec<Operation> {
@@ -151,17 +471,24 @@ impl Chunk {
                 ops.extend_from_slice(&self.encode_expr(ex));
             },
             &Expr::Assign(ref n, ref ex) => {
-                if let Some(idx) = self.constants.iter().position(|x| x == &LoxType::String(n.lexeme.clone())) {
-                    ops.push(Operation::Assign);
-                    ops.push(Operation::Operand(idx as u64));
-                    ops.extend_from_slice(&self.encode_expr(ex));
-                }
+                ops.extend_from_slice(&self.encode_expr(ex));
+                ops.push(Operation::Line(n.span.line));
+                let access = self.access(n);
+                ops.extend_from_slice(&self.encode_set(access,&n.lexeme));
             },
-            &Expr::Unary(_, ref ex) => {
-                ops.push(Operation::Negate);
+            &Expr::Unary(ref token, ref ex) => {
                 ops.extend_from_slice(&self.encode_expr(ex));
+                ops.push(Operation::Line(token.span.line));
+                match token.token {
+                    TokenType::Bang => ops.push(Operation::Not),
+                    TokenType::Minus => ops.push(Operation::Negate),
+                    _ => self.unsupported(token)
+                }
             },
             &Expr::Binary(ref one, ref token, ref two) => {
+                ops.extend_from_slice(&self.encode_expr(one));
+                ops.extend_from_slice(&self.encode_expr(two));
+                ops.push(Operation::Line(token.span.line));
                 match token.token {
                     TokenType::Plus => {
                         ops.push(Operation::Add);
@@ -177,117 +504,533 @@ impl Chunk {
                     TokenType::Slash => {
                         ops.push(Operation::Divide);
                     },
-                    _ => {}
+                    TokenType::EqualEqual => ops.push(Operation::Equals),
+                    TokenType::BangEqual => ops.push(Operation::NotEquals),
+                    _ => self.unsupported(token)
                 }
+            },
+            // the right operand is only evaluated if the left one doesn't
+            // decide the result, which is whichever operand decided it
+            &Expr::Logical(ref one,ref token,ref two) if matches!(token.token,TokenType::And | TokenType::Or) => {
                 ops.extend_from_slice(&self.encode_expr(one));
+                ops.push(Operation::Line(token.span.line));
+                let op = if token.token == TokenType::And { Operation::And } else { Operation::Or };
+                let jump = emit_jump(&mut ops,op);
                 ops.extend_from_slice(&self.encode_expr(two));
+                patch_jump(&mut ops,jump);
             },
             &Expr::Logical(ref one,ref token, ref two) => {
-                ops.push(match token.token {
-                    TokenType::And => Operation::And,
-                    TokenType::Or => Operation::Or,
-                    TokenType::EqualEqual => Operation::Equals,
-                    TokenType::BangEqual => Operation::NotEquals,
-                    TokenType::Greater => Operation::Greater,
-                    TokenType::GreaterEqual => Operation::GreaterEqual,
-                    TokenType::Less => Operation::Lesser,
-                    TokenType::LessEqual => Operation::LesserEqual,
-                    _ => Operation::Return
-                });
                 ops.extend_from_slice(&self.encode_expr(one));
                 ops.extend_from_slice(&self.encode_expr(two));
+                ops.push(Operation::Line(token.span.line));
+                match token.token {
+                    TokenType::EqualEqual => ops.push(Operation::Equals),
+                    TokenType::BangEqual => ops.push(Operation::NotEquals),
+                    TokenType::Greater => ops.push(Operation::Greater),
+                    TokenType::GreaterEqual => ops.push(Operation::GreaterEqual),
+                    TokenType::Less => ops.push(Operation::Lesser),
+                    TokenType::LessEqual => ops.push(Operation::LesserEqual),
+                    _ => self.unsupported(token)
+                }
             },
             &Expr::Literal(ref v) => {
                 ops.extend_from_slice(&self.op_const(v.clone()));
             },
             &Expr::Variable(ref t) => {
-                if let Some(_idx) = self.constants.iter().position(|x| x == &LoxType::String(t.lexeme.clone())) {
-                    ops.push(Operation::Get);
-                    ops.push(Operation::Operand(_idx as u64)); // idx = u16
+                ops.push(Operation::Line(t.span.line));
+                let access = self.access(t);
+                ops.extend_from_slice(&self.encode_get(access,&t.lexeme));
+            },
+            &Expr::This(ref t) => {
+                ops.push(Operation::Line(t.span.line));
+                let this = self.resolution.access(t);
+                ops.extend_from_slice(&self.encode_get(this,"this"));
+            },
+            &Expr::Super(ref t,ref method) => {
+                ops.push(Operation::Line(t.span.line));
+                let this = self.resolution.access(method);
+                let superclass = self.resolution.access(t);
+                ops.extend_from_slice(&self.encode_get(this,"this"));
+                ops.extend_from_slice(&self.encode_get(superclass,"super"));
+                ops.push(Operation::GetSuper);
+                ops.push(self.short_const(LoxType::String(method.lexeme.clone())));
+            },
+            &Expr::Get(ref object,ref name) => {
+                ops.extend_from_slice(&self.encode_expr(object));
+                ops.push(Operation::Line(name.span.line));
+                ops.push(Operation::GetProperty);
+                ops.push(self.short_const(LoxType::String(name.lexeme.clone())));
+            },
+            &Expr::Set(ref object,ref name,ref ex) => {
+                ops.extend_from_slice(&self.encode_expr(object));
+                ops.extend_from_slice(&self.encode_expr(ex));
+                ops.push(Operation::Line(name.span.line));
+                ops.push(Operation::SetProperty);
+                ops.push(self.short_const(LoxType::String(name.lexeme.clone())));
+            },
+            &Expr::Call(ref callee,ref paren,ref args) => {
+                ops.extend_from_slice(&self.encode_expr(callee));
+                for a in args {
+                    ops.extend_from_slice(&self.encode_expr(a));
+                }
+                ops.push(Operation::Line(paren.span.line));
+                ops.push(Operation::Call);
+                // the parser allows at most MAX_ARGS
+                ops.push(Operation::Byte(args.len() as u8));
+            },
+            &Expr::Function(ref keyword,ref params,ref body) => {
+                ops.push(Operation::Line(keyword.span.line));
+                ops.extend_from_slice(&self.encode_function(None,keyword,params,body,false));
+            }
+        }
+        ops
+    }
+
+    // An operator the parser accepted but there's no op for.
+    fn unsupported(&mut self,t: &Token) {
+        self.loader.error(format!("Unsupported operator '{}'",t.lexeme),t.span);
+    }
+
+    fn encode_get(&mut self,access: Access,name: &str) -> Vec<Operation> {
+        match access {
+            Access::Local(slot) => with_operand(Operation::GetLocal,Operation::LongGetLocal,slot as usize),
+            Access::Upvalue(i) => vec![Operation::GetUpvalue,Operation::Byte(i)],
+            Access::Global => {
+                let idx = self.set_const(LoxType::String(name.to_string()));
+                with_operand(Operation::GetGlobal,Operation::LongGetGlobal,idx)
+            }
+        }
+    }
+
+    // After the value to assign.
+    fn encode_set(&mut self,access: Access,name: &str) -> Vec<Operation> {
+        match access {
+            Access::Local(slot) => with_operand(Operation::SetLocal,Operation::LongSetLocal,slot as usize),
+            Access::Upvalue(i) => vec![Operation::SetUpvalue,Operation::Byte(i)],
+            Access::Global => {
+                let idx = self.set_const(LoxType::String(name.to_string()));
+                with_operand(Operation::SetGlobal,Operation::LongSetGlobal,idx)
+            }
+        }
+    }
+
+    // Compiles a function body into a chunk of its own, which goes into this
+    // chunk's constants, and emits the closure op that captures its upvalues.
+    // Parameters are the first locals of the call, after `this` for methods.
+    // `key` is the token the resolver keeps the function's captures under.
+    fn encode_function(&mut self,name: Option<&Token>,key: &Token,params: &[Token],body: &Statement,method: bool) -> Vec<Operation> {
+        // errors in the body are recorded by the same loader
+        let mut chunk = Chunk::with_loader(std::mem::take(&mut self.loader));
+        chunk.file = self.file.clone();
+        chunk.resolution = self.resolution.clone();
+        chunk.function = true;
+        if let Some(n) = name {
+            chunk.ops.push(Operation::Line(n.span.line));
+        }
+        if method {
+            chunk.locals.push(0);
+        }
+        for _ in params {
+            chunk.locals.push(0);
+        }
+        let body_ops = chunk.encode_statement(body.clone());
+        chunk.ops.extend_from_slice(&body_ops);
+        chunk.check_constants(key.span);
+        self.loader = std::mem::take(&mut chunk.loader);
+        self.global_uses.append(&mut chunk.global_uses);
+
+        let captures = self.resolution.captures(key).to_vec();
+        let function = Function {
+            name: name.map(|n| n.lexeme.clone()),
+            params: params.iter().map(|p| p.lexeme.clone()).collect(),
+            upvalue_count: captures.len(),
+            file: chunk.file.clone(),
+            bytecode: chunk.to_bytecode()
+        };
+        let mut ops = vec![Operation::Closure,self.short_const(LoxType::Function(Rc::new(function)))];
+        for c in captures {
+            ops.push(Operation::Byte(c.is_local as u8));
+            ops.push(Operation::Short(c.index));
+        }
+        ops
+    }
+
+    // Modules are compiled like a program of their own, the first time they're
+    // imported. Problems end up in the loader's errors.
+    fn encode_import(&mut self,t: &Token,alias: Option<&Token>) -> Vec<Operation> {
+        let name = match t.literal {
+            Some(LoxType::String(ref s)) => s.clone(),
+            _ => t.lexeme.trim_matches('"').to_string()
+        };
+        let (f,exports) = match self.loader.import(&name,t.span) {
+            Import::Compiled(f,exports) => (f,exports),
+            Import::Parsed(path,statements) => {
+                let file: Option<Rc<str>> = self.loader.current_file().map(Rc::from);
+                let exports = exported_names(&statements);
+                let errors = self.loader.error_count();
+                let mut chunk = Chunk::with_loader(std::mem::take(&mut self.loader));
+                chunk.file = file.clone();
+                chunk.encode_program(statements,true);
+                self.loader = std::mem::take(&mut chunk.loader);
+                if self.loader.error_count() > errors {
+                    self.loader.abandon();
+                    return Vec::new();
                 }
+                let f = Rc::new(Function {
+                    name: file.as_ref().map(|f| f.to_string()),
+                    params: Vec::new(),
+                    upvalue_count: 0,
+                    file,
+                    bytecode: chunk.to_bytecode()
+                });
+                self.loader.finish(path,f.clone(),exports.clone());
+                (f,exports)
             },
-            _ => {}
+            Import::Failed => return Vec::new()
+        };
+
+        let module = self.short_const(LoxType::Function(f));
+        let mut ops = vec![Operation::Line(t.span.line),Operation::Import,module];
+        ops.push(Operation::ImportModule);
+        ops.push(module);
+        ops.push(Operation::Short(exports.len() as u16));
+        for e in &exports {
+            ops.push(self.short_const(LoxType::String(e.clone())));
+        }
+        if let Some(a) = alias {
+            // imports are only allowed at the top level, so this is a global
+            self.globals.insert(a.lexeme.clone());
+            let alias_idx = self.set_const(LoxType::String(a.lexeme.clone()));
+            ops.extend_from_slice(&with_operand(Operation::DefineGlobal,Operation::LongDefineGlobal,alias_idx));
+        } else {
+            self.globals.extend(exports);
+            ops.push(Operation::ImportNames);
         }
         ops
     }
 
     pub fn encode_statement(&mut self,st: Statement) -> Vec<Operation> {
-        let mut ops = Vec::new();
-        ops.reserve(256);
+        let mut ops = Vec::with_capacity(256);
         match st {
-            Statement::Expression(e) => ops.extend_from_slice(&self.encode_expr(&e)),
+            Statement::Expression(e) => {
+                ops.extend_from_slice(&self.encode_expr(&e));
+                ops.extend_from_slice(&encode_pop(1));
+            },
             Statement::Variable(name,e) => {
-                let name_idx = self.set_const(LoxType::String(name.lexeme.clone()));
-                if self.curr_depth > 0 { self.var_count += 1; };
-                ops.push(Operation::Var);
-                ops.push(Operation::Operand(name_idx as u64)); // push to stack
-                if let Some(expr) = e {
-                    ops.push(Operation::Assign);
-                    ops.push(Operation::Operand(name_idx as u64));
-                    ops.extend_from_slice(&self.encode_expr(&expr));
+                let access = self.declare(&name.lexeme);
+                match (e,access) {
+                    // a local starts out nil
+                    (None,Access::Local(_)) => {
+                        ops.push(Operation::Line(name.span.line));
+                        ops.push(Operation::Local);
+                    },
+                    (e,access) => {
+                        let value = e.unwrap_or(Expr::Literal(LoxType::Nil));
+                        ops.push(Operation::Line(name.span.line));
+                        ops.extend_from_slice(&self.encode_expr(&value));
+                        ops.push(Operation::Line(name.span.line));
+                        ops.extend_from_slice(&self.encode_define(access,&name.lexeme));
+                    }
                 }
             },
-            Statement::Block(statements) => {
+            Statement::Block(_,statements) => {
                 self.curr_depth += 1;
                 for s in statements {
                     let new_ops = self.encode_statement((*s).clone());
                     ops.extend_from_slice(&new_ops);
                 }
-                if self.var_count > 0 {
-                    ops.push(Operation::Pop);
-                    ops.push(Operation::Operand(self.var_count as u64));
+                let count = self.end_scope();
+                ops.extend_from_slice(&encode_pop(count));
+            },
+            Statement::If(_,expr,first,else_path) => {
+                ops.extend_from_slice(&self.encode_expr(&expr));
+                let then_jump = emit_jump(&mut ops,Operation::JumpIfFalse);
+                ops.extend_from_slice(&self.encode_statement((*first).clone()));
+                match else_path {
+                    Some(else_branch) => {
+                        let else_ops = self.encode_statement((*else_branch).clone());
+                        let else_jump = emit_jump(&mut ops,Operation::Jump);
+                        // the then jump goes over the else jump, which has to be as
+                        // wide as it'll end up before the then jump is patched
+                        if encoded_len(&else_ops) > u16::MAX as usize {
+                            widen_jump(&mut ops,else_jump);
+                        }
+                        patch_jump(&mut ops,then_jump);
+                        ops.extend_from_slice(&else_ops);
+                        patch_jump(&mut ops,else_jump);
+                    },
+                    None => patch_jump(&mut ops,then_jump)
+                }
+            },
+            Statement::While(_,expr,first,increment) => {
+                ops.extend_from_slice(&self.encode_expr(&expr));
+                let exit_jump = emit_jump(&mut ops,Operation::JumpIfFalse);
+                self.loops.push(self.locals.len());
+                ops.extend_from_slice(&self.encode_statement((*first).clone()));
+                self.loops.pop();
+                let next = ops.len();
+                if let Some(ref i) = increment {
+                    ops.extend_from_slice(&self.encode_expr(i));
+                    ops.extend_from_slice(&encode_pop(1));
+                }
+                // the jump back goes over the exit jump, which has to be as wide
+                // as it'll end up before the jump back is measured
+                if encoded_len(&ops[exit_jump + 2..]) + 5 > u16::MAX as usize {
+                    widen_jump(&mut ops,exit_jump);
+                }
+                emit_jump_back(&mut ops,0);
+                patch_jump(&mut ops,exit_jump);
+                patch_loop_jumps(&mut ops,next);
+            },
+            Statement::Break(t) | Statement::Continue(t) => {
+                // the locals declared in the loop's body so far go first
+                let outside = *self.loops.last().unwrap();
+                ops.push(Operation::Line(t.span.line));
+                ops.extend_from_slice(&encode_pop(self.locals.len() - outside));
+                ops.push(if t.token == TokenType::Break { Operation::Break } else { Operation::Continue });
+                // always long, the ops around it may already have jumps over it measured
+                ops.push(Operation::LongJump);
+                ops.push(Operation::Word(0));
+            },
+            Statement::Function(name,params,body) => {
+                // declared before the body is compiled, so a local function can
+                // capture the slot it's put in to recurse
+                let access = self.declare(&name.lexeme);
+                let function_ops = self.encode_function(Some(&name),&name,&params,&body,false);
+                ops.push(Operation::Line(name.span.line));
+                ops.extend_from_slice(&function_ops);
+                ops.extend_from_slice(&self.encode_define(access,&name.lexeme));
+            },
+            Statement::Class(name,superclass,methods) => {
+                let access = self.declare(&name.lexeme);
+                ops.push(Operation::Line(name.span.line));
+                ops.push(Operation::Class);
+                ops.push(self.short_const(LoxType::String(name.lexeme.clone())));
+                ops.extend_from_slice(&self.encode_define(access,&name.lexeme));
+
+                // the superclass lives in a scope of its own around the methods,
+                // which capture it as `super`
+                if let Some(ref s) = superclass {
+                    self.curr_depth += 1;
+                    let superclass = self.declare("super");
+                    ops.extend_from_slice(&self.encode_expr(s));
+                    ops.extend_from_slice(&self.encode_define(superclass,"super"));
+                    ops.extend_from_slice(&self.encode_get(access,&name.lexeme));
+                    ops.extend_from_slice(&self.encode_get(superclass,"super"));
+                    ops.push(Operation::Inherit);
+                }
+
+                for m in &methods {
+                    if let &Statement::Function(ref method,ref params,ref body) = &**m {
+                        let method_name = self.short_const(LoxType::String(method.lexeme.clone()));
+                        let class_ops = self.encode_get(access,&name.lexeme);
+                        let function_ops = self.encode_function(Some(method),method,params,body,true);
+                        ops.push(Operation::Line(method.span.line));
+                        ops.extend_from_slice(&class_ops);
+                        ops.extend_from_slice(&function_ops);
+                        ops.push(Operation::Method);
+                        ops.push(method_name);
+                    }
+                }
+
+                if superclass.is_some() {
+                    let count = self.end_scope();
+                    ops.extend_from_slice(&encode_pop(count));
                 }
-                self.var_count = 0;
-                self.curr_depth -= 1;
             },
-            Statement::If(expr,first,else_path) => {
-                let expr_ops = self.encode_expr(&expr);
-                let if_branch_ops = self.encode_statement((*first).clone());
-                ops.push(Operation::JumpIfFalse);
-                ops.extend_from_slice(&expr_ops);
-                ops.push(Operation::Operand((expr_ops.len() + if_branch_ops.len()) as u64));
-                ops.extend_from_slice(&if_branch_ops);
-                if let Some(else_branch) = else_path { ops.extend_from_slice(&self.encode_statement((*else_branch).clone())); };
+            Statement::Print(t,e) => {
+                ops.push(Operation::Line(t.span.line));
+                ops.extend_from_slice(&self.encode_expr(&e));
+                ops.push(Operation::Line(t.span.line));
+                ops.push(Operation::Print);
             },
-            Statement::While(expr,first) => {
-                let expr_ops = self.encode_expr(&expr);
-                let block_ops = self.encode_statement((*first).clone());
-                println!("{:?}",block_ops);
-                ops.extend_from_slice(&block_ops);
-                ops.push(Operation::JumpBackIfTrue);
-                ops.extend_from_slice(&expr_ops);
-                ops.push(Operation::Operand((expr_ops.len() + block_ops.len() + 2) as u64));
+            Statement::Import(t,alias) => ops.extend_from_slice(&self.encode_import(&t,alias.as_ref())),
+            Statement::Export(_,declaration) => ops.extend_from_slice(&self.encode_statement((*declaration).clone())),
+            Statement::Return(t,e) => {
+                let value = e.unwrap_or(Expr::Literal(LoxType::Nil));
+                ops.push(Operation::Line(t.span.line));
+                ops.extend_from_slice(&self.encode_expr(&value));
+                ops.push(Operation::Line(t.span.line));
+                ops.push(Operation::Return);
             }
-            _ => {}
         }
 
         ops
     }
 
-    pub fn encode_ops(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::new();
-        for op in &self.ops {
-    //        println!("{:?}",op);
-            bytes.extend_from_slice(&op.to_bytes())
+    pub fn to_bytecode(&self) -> Bytecode {
+        self.to_bytecode_from(0)
+    }
+
+    // Encodes only the ops from `start` onwards, for appending freshly compiled
+    // statements to code that's already been run.
+    pub fn to_bytecode_from(&self,start: usize) -> Bytecode {
+        let mut code: Vec<u8> = Vec::new();
+        let mut lines = LineTable::new();
+        let mut line = self.ops[..start].iter().rev().find_map(|op| match op {
+            &Operation::Line(l) => Some(l),
+            _ => None
+        }).unwrap_or(0);
+
+        for op in &self.ops[start..] {
+            if let &Operation::Line(l) = op {
+                line = l;
+            }
+            let bytes = op.to_bytes();
+            lines.push(bytes.len(),line);
+            code.extend_from_slice(&bytes);
+        }
+        Bytecode {
+            code,
+            constants: self.constants.clone(),
+            lines
+        }
+    }
+
+    // Compiles more of the program, e.g. the next line of the repl, so the
+    // globals used by its functions may still be declared later. Nothing of
+    // statements that fail to compile is kept.
+    pub fn compile_to_ops(&mut self,st: Vec<Rc<Statement>>) -> Result<(),Vec<CompileError>> {
+        self.compile_statements(st,false)
+    }
+
+    fn compile_statements(&mut self,st: Vec<Rc<Statement>>,whole: bool) -> Result<(),Vec<CompileError>> {
+        let start = self.ops.len();
+        let globals = self.globals.clone();
+        self.encode_program(st,whole);
+        let errors = self.loader.take_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            self.ops.truncate(start);
+            self.globals = globals;
+            Err(errors)
         }
-        bytes
     }
 
-    pub fn compile_to_ops(&mut self,st: Vec<Rc<Statement>>) {
+    fn encode_program(&mut self,st: Vec<Rc<Statement>>,whole: bool) {
+        let (resolution,warnings) = Resolver::new().resolve(&st);
+        for w in warnings {
+            self.loader.add_warning(w);
+        }
+        match resolution {
+            Ok(r) => self.resolution = Rc::new(r),
+            Err(errors) => {
+                for e in errors {
+                    self.loader.add_error(e);
+                }
+                return;
+            }
+        }
+        let mut constants_ok = true;
         for s in st.into_iter() {
             let enc = self.encode_statement((*s).clone());
+            if constants_ok {
+                constants_ok = self.check_constants(line_span(&enc));
+            }
             self.ops.extend_from_slice(&enc);
         }
+        self.check_globals(whole);
     }
 }
 
+// Emits a forward jump to be pointed somewhere by `patch_jump` once the code it
+// jumps over has been emitted, returns where it is.
+fn emit_jump(ops: &mut Vec<Operation>,op: Operation) -> usize {
+    ops.push(op);
+    ops.push(Operation::Short(0));
+    ops.len() - 2
+}
 
-/*
-while (n < 100) { n = n + 1; }
-block = ASSIGN INDEX CONSTANT INDEX
-block = 4 bytes
-JUMP_BACK_IF_FALSE LESSER GET VAR_INDEX CONSTANT INDEX OFFSET
-if n < 100, jump back 4 bytes + amount of bytes in jump expr
+// Points the jump at `at` to the end of `ops`. Offsets count encoded bytes from
+// after the jump, those too far for 16 bits take the long variant.
+fn patch_jump(ops: &mut [Operation],at: usize) {
+    let len = encoded_len(&ops[at + 2..]);
+    if len > u16::MAX as usize {
+        widen_jump(ops,at);
+    }
+    ops[at + 1] = match ops[at + 1] {
+        Operation::Word(_) => Operation::Word(len as u32),
+        _ => Operation::Short(len as u16)
+    };
+}
 
+fn widen_jump(ops: &mut [Operation],at: usize) {
+    ops[at] = match ops[at] {
+        Operation::JumpIfTrue => Operation::LongJumpIfTrue,
+        Operation::JumpIfFalse => Operation::LongJumpIfFalse,
+        Operation::Jump => Operation::LongJump,
+        Operation::And => Operation::LongAnd,
+        Operation::Or => Operation::LongOr,
+        op => op
+    };
+    ops[at + 1] = Operation::Word(0);
+}
 
-*/
+// Jumps back to `start` in `ops`, over the jump's own bytes too.
+fn emit_jump_back(ops: &mut Vec<Operation>,start: usize) {
+    let len = encoded_len(&ops[start..]) + 3;
+    if len <= u16::MAX as usize {
+        ops.push(Operation::JumpBack);
+        ops.push(Operation::Short(len as u16));
+    } else {
+        ops.push(Operation::LongJumpBack);
+        ops.push(Operation::Word((len + 2) as u32));
+    }
+}
+
+// Points the jumps marked in a loop's `ops` at its end for a break, or at
+// `next`, where the increment and the jump back are, for a continue. Those of
+// loops nested in it were patched already and their markers dropped.
+fn patch_loop_jumps(ops: &mut Vec<Operation>,next: usize) {
+    for i in 0..ops.len() {
+        let target = match ops[i] {
+            Operation::Break => ops.len(),
+            Operation::Continue => next,
+            _ => continue
+        };
+        ops[i + 2] = Operation::Word(encoded_len(&ops[i + 3..target]) as u32);
+    }
+    ops.retain(|op| !matches!(op,Operation::Break | Operation::Continue));
+}
+
+// Drops `count` values, a byte's worth at a time.
+fn encode_pop(count: usize) -> Vec<Operation> {
+    let mut ops = Vec::new();
+    let mut left = count;
+    while left > 0 {
+        let n = left.min(u8::MAX as usize);
+        ops.push(Operation::Pop);
+        ops.push(Operation::Byte(n as u8));
+        left -= n;
+    }
+    ops
+}
+
+// Compiles a whole program, errors in the modules it imports included.
+pub fn compile(st: Vec<Rc<Statement>>,loader: Loader) -> Result<Chunk,Vec<CompileError>> {
+    let mut chunk = Chunk::with_loader(loader);
+    chunk.compile_statements(st,true)?;
+    Ok(chunk)
+}
+
+
+// What a module exposes: the declarations marked with export, or all of its
+// top-level declarations if there aren't any.
+fn exported_names(statements: &[Rc<Statement>]) -> Vec<String> {
+    fn declared(s: &Statement) -> Option<String> {
+        match s {
+            &Statement::Variable(ref t,_) | &Statement::Function(ref t,_,_) | &Statement::Class(ref t,_,_) => Some(t.lexeme.clone()),
+            _ => None
+        }
+    }
+    let exports: Vec<&Statement> = statements.iter().filter_map(|s| match **s {
+        Statement::Export(_,ref d) => Some(&**d),
+        _ => None
+    }).collect();
+    if exports.is_empty() {
+        statements.iter().filter_map(|s| declared(s)).collect()
+    } else {
+        exports.into_iter().filter_map(declared).collect()
+    }
+}
@@ -0,0 +1,156 @@
+use super::compiler::{Operation,Bytecode};
+use super::lines::LineTable;
+use super::lox_type::*;
+use std::fmt::Write;
+
+// Lists the ops one after the other, the way the vm runs them, with the
+// captures and exports that follow an op indented under it.
+pub struct Disassembler<'a> {
+    code: &'a [u8],
+    constants: &'a [LoxType],
+    lines: &'a LineTable,
+    idx: usize,
+    out: String
+}
+
+type DisasmResult = Result<(),String>;
+
+impl<'a> Disassembler<'a> {
+    pub fn new(b: &'a Bytecode) -> Disassembler<'a> {
+        Disassembler {
+            code: &b.code,
+            constants: &b.constants,
+            lines: &b.lines,
+            idx: 0,
+            out: String::new()
+        }
+    }
+
+    // Functions are listed after the code that declares them.
+    pub fn disassemble(mut self) -> Result<String,String> {
+        while self.idx < self.code.len() {
+            self.op()?;
+        }
+        for c in self.constants {
+            if let &LoxType::Function(ref f) = c {
+                let _ = writeln!(self.out,"== {} ==",f);
+                self.out.push_str(&disassemble(&f.bytecode)?);
+            }
+        }
+        Ok(self.out)
+    }
+
+    fn read(&mut self) -> Result<u8,String> {
+        if self.idx >= self.code.len() {
+            return Err(format!("unexpected end of code at {:04}",self.idx));
+        }
+        self.idx += 1;
+        Ok(self.code[self.idx - 1])
+    }
+
+    fn read_u16(&mut self) -> Result<u16,String> {
+        Ok(u16::from_le_bytes([self.read()?,self.read()?]))
+    }
+
+    fn constant(&self,i: usize) -> String {
+        match self.constants.get(i) {
+            Some(&LoxType::String(ref s)) => format!("'{}'",s),
+            Some(c) => format!("{}",c),
+            None => "<invalid constant>".to_string()
+        }
+    }
+
+    fn line(&mut self,offset: usize,depth: usize,text: String) {
+        let line_col = match self.lines.line_at(offset) {
+            Some(l) => format!("{:>4}",l),
+            None => "   ?".to_string()
+        };
+        let _ = writeln!(self.out,"{:04} {}  {}{}",offset,line_col,"  ".repeat(depth),text);
+    }
+
+    fn op(&mut self) -> DisasmResult {
+        let offset = self.idx;
+        let byte = self.read()?;
+        let op = Operation::from_byte(byte).ok_or(format!("unknown opcode {} at {:04}",byte,offset))?;
+        match op {
+            Operation::Constant | Operation::GetGlobal | Operation::DefineGlobal | Operation::SetGlobal => {
+                let i = self.read()? as usize;
+                let c = self.constant(i);
+                self.line(offset,0,format!("{:?} {} ; {}",op,i,c));
+            },
+            Operation::LongConstant | Operation::LongGetGlobal | Operation::LongDefineGlobal | Operation::LongSetGlobal |
+            Operation::Class | Operation::GetProperty | Operation::Method | Operation::SetProperty | Operation::GetSuper | Operation::Import => {
+                let i = self.read_u16()? as usize;
+                let c = self.constant(i);
+                self.line(offset,0,format!("{:?} {} ; {}",op,i,c));
+            },
+            Operation::Closure => {
+                let i = self.read_u16()? as usize;
+                let c = self.constant(i);
+                self.line(offset,0,format!("{:?} {} ; {}",op,i,c));
+                let count = match self.constants.get(i) {
+                    Some(&LoxType::Function(ref f)) => f.upvalue_count,
+                    _ => 0
+                };
+                for _ in 0..count {
+                    let at = self.idx;
+                    let is_local = self.read()? != 0;
+                    let index = self.read_u16()? as usize;
+                    let text = if is_local {
+                        format!("| local {}",index)
+                    } else {
+                        format!("| upvalue {}",index)
+                    };
+                    self.line(at,1,text);
+                }
+            },
+            Operation::ImportModule => {
+                let i = self.read_u16()? as usize;
+                let c = self.constant(i);
+                self.line(offset,0,format!("{:?} {} ; {}",op,i,c));
+                let count = self.read_u16()?;
+                for _ in 0..count {
+                    let at = self.idx;
+                    let name = self.read_u16()? as usize;
+                    let text = format!("| export {} ; {}",name,self.constant(name));
+                    self.line(at,1,text);
+                }
+            },
+            Operation::GetUpvalue | Operation::GetLocal | Operation::SetUpvalue | Operation::SetLocal | Operation::Pop | Operation::Call => {
+                let i = self.read()?;
+                self.line(offset,0,format!("{:?} {}",op,i));
+            },
+            Operation::LongGetLocal | Operation::LongSetLocal => {
+                let i = self.read_u16()?;
+                self.line(offset,0,format!("{:?} {}",op,i));
+            },
+            Operation::Jump | Operation::JumpIfTrue | Operation::JumpIfFalse | Operation::And | Operation::Or |
+            Operation::LongJump | Operation::LongJumpIfTrue | Operation::LongJumpIfFalse | Operation::LongAnd | Operation::LongOr => {
+                let jump = self.jump_operand(op)?;
+                self.line(offset,0,format!("{:?} {} -> {:04}",op,jump,self.idx + jump));
+            },
+            Operation::JumpBack | Operation::LongJumpBack => {
+                let jump = self.jump_operand(op)?;
+                self.line(offset,0,format!("{:?} {} -> {:04}",op,jump,self.idx.saturating_sub(jump)));
+            },
+            Operation::Byte(_) | Operation::Short(_) | Operation::Word(_) | Operation::Line(_)
+                | Operation::Break | Operation::Continue => unreachable!(),
+            _ => self.line(offset,0,format!("{:?}",op))
+        }
+        Ok(())
+    }
+
+    fn jump_operand(&mut self,op: Operation) -> Result<usize,String> {
+        match op {
+            Operation::LongJump | Operation::LongJumpBack | Operation::LongJumpIfTrue | Operation::LongJumpIfFalse |
+            Operation::LongAnd | Operation::LongOr => {
+                Ok(u32::from_le_bytes([self.read()?,self.read()?,self.read()?,self.read()?]) as usize)
+            },
+            _ => Ok(self.read_u16()? as usize)
+        }
+    }
+}
+
+pub fn disassemble(b: &Bytecode) -> Result<String,String> {
+    Disassembler::new(b).disassemble()
+}
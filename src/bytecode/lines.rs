@@ -0,0 +1,52 @@
+// Maps bytecode offsets back to source lines. Consecutive bytes from the same
+// line are stored as a single (byte count, line) run, since most lines compile
+// down to several bytes.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct LineTable {
+    runs: Vec<(u32,i32)>
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    pub fn from_runs(runs: Vec<(u32,i32)>) -> LineTable {
+        LineTable { runs }
+    }
+
+    pub fn runs(&self) -> &[(u32,i32)] {
+        &self.runs
+    }
+
+    // Records that the next `len` bytes of code came from `line`.
+    pub fn push(&mut self,len: usize,line: i32) {
+        if len == 0 {
+            return;
+        }
+        if let Some(last) = self.runs.last_mut() {
+            if last.1 == line {
+                last.0 += len as u32;
+                return;
+            }
+        }
+        self.runs.push((len as u32,line));
+    }
+
+    pub fn extend(&mut self,other: &LineTable) {
+        for &(len,line) in &other.runs {
+            self.push(len as usize,line);
+        }
+    }
+
+    pub fn line_at(&self,offset: usize) -> Option<i32> {
+        let mut end = 0;
+        for &(len,line) in &self.runs {
+            end += len as usize;
+            if offset < end {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,188 @@
+use super::lox_type::*;
+use super::compiler::Bytecode;
+use super::lines::LineTable;
+use std::io::{self,Read,Write};
+use std::rc::Rc;
+
+/*
+.loxc layout, all integers little endian:
+MAGIC "LOXC" VERSION u8 BODY
+BODY:
+CONSTANT_COUNT u32 (TAG u8 PAYLOAD)*
+CODE_LEN u32 CODE
+LINE_RUNS u32 (BYTE_COUNT u32 LINE i32)*
+a function constant's payload is its name, parameters, upvalue count, file and its own BODY
+*/
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u8 = 11;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,msg.to_string())
+}
+
+pub fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write<W: Write>(w: &mut W,b: &Bytecode) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    write_body(w,b)
+}
+
+fn write_string<W: Write>(w: &mut W,s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_body<W: Write>(w: &mut W,b: &Bytecode) -> io::Result<()> {
+    w.write_all(&(b.constants.len() as u32).to_le_bytes())?;
+    for c in &b.constants {
+        match c {
+            &LoxType::Nil => w.write_all(&[TAG_NIL])?,
+            &LoxType::Boolean(v) => w.write_all(&[TAG_BOOL,v as u8])?,
+            &LoxType::Number(n) => {
+                w.write_all(&[TAG_NUMBER])?;
+                w.write_all(&n.to_le_bytes())?;
+            },
+            &LoxType::String(ref s) => {
+                w.write_all(&[TAG_STRING])?;
+                write_string(w,s)?;
+            },
+            &LoxType::Function(ref f) => {
+                w.write_all(&[TAG_FUNCTION])?;
+                match f.name {
+                    Some(ref n) => {
+                        w.write_all(&[1])?;
+                        write_string(w,n)?;
+                    },
+                    None => w.write_all(&[0])?
+                }
+                w.write_all(&(f.params.len() as u32).to_le_bytes())?;
+                for p in &f.params {
+                    write_string(w,p)?;
+                }
+                w.write_all(&(f.upvalue_count as u32).to_le_bytes())?;
+                match f.file {
+                    Some(ref file) => {
+                        w.write_all(&[1])?;
+                        write_string(w,file)?;
+                    },
+                    None => w.write_all(&[0])?
+                }
+                write_body(w,&f.bytecode)?;
+            },
+            &LoxType::Closure(_) | &LoxType::Class(_) | &LoxType::Instance(_) | &LoxType::BoundMethod(_) | &LoxType::Module(_) => {
+                return Err(invalid("runtime values can't be written as constants"))
+            }
+        }
+    }
+    w.write_all(&(b.code.len() as u32).to_le_bytes())?;
+    w.write_all(&b.code)?;
+    w.write_all(&(b.lines.runs().len() as u32).to_le_bytes())?;
+    for &(len,line) in b.lines.runs() {
+        w.write_all(&len.to_le_bytes())?;
+        w.write_all(&line.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_bytes<R: Read>(r: &mut R,len: usize) -> io::Result<Vec<u8>> {
+    let mut b = Vec::new();
+    r.take(len as u64).read_to_end(&mut b)?;
+    if b.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(b)
+}
+
+pub fn read<R: Read>(r: &mut R) -> io::Result<Bytecode> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a .loxc file"));
+    }
+    if read_u8(r)? != VERSION {
+        return Err(invalid("unsupported .loxc version"));
+    }
+    read_body(r)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    String::from_utf8(read_bytes(r,len)?).map_err(|_| invalid("constant string is not utf-8"))
+}
+
+fn read_body<R: Read>(r: &mut R) -> io::Result<Bytecode> {
+    let count = read_u32(r)?;
+    let mut constants = Vec::new();
+    for _ in 0..count {
+        constants.push(match read_u8(r)? {
+            TAG_NIL => LoxType::Nil,
+            TAG_BOOL => LoxType::Boolean(read_u8(r)? != 0),
+            TAG_NUMBER => {
+                let mut b = [0; 8];
+                r.read_exact(&mut b)?;
+                LoxType::Number(f64::from_le_bytes(b))
+            },
+            TAG_STRING => LoxType::String(read_string(r)?),
+            TAG_FUNCTION => {
+                let name = match read_u8(r)? {
+                    0 => None,
+                    _ => Some(read_string(r)?)
+                };
+                let count = read_u32(r)?;
+                let mut params = Vec::new();
+                for _ in 0..count {
+                    params.push(read_string(r)?);
+                }
+                // the vm makes room for them up front, more than the resolver
+                // allows can only come from a broken file
+                let upvalue_count = read_u32(r)? as usize;
+                if upvalue_count > u8::MAX as usize + 1 {
+                    return Err(invalid("too many upvalues"));
+                }
+                let file = match read_u8(r)? {
+                    0 => None,
+                    _ => Some(Rc::from(read_string(r)?))
+                };
+                let bytecode = read_body(r)?;
+                LoxType::Function(Rc::new(Function { name, params, upvalue_count, file, bytecode }))
+            },
+            _ => return Err(invalid("unknown constant tag"))
+        });
+    }
+
+    let len = read_u32(r)? as usize;
+    let code = read_bytes(r,len)?;
+
+    let count = read_u32(r)?;
+    let mut runs = Vec::new();
+    for _ in 0..count {
+        runs.push((read_u32(r)?,read_u32(r)? as i32));
+    }
+    Ok(Bytecode {
+        code,
+        constants,
+        lines: LineTable::from_runs(runs)
+    })
+}
@@ -1,4 +1,7 @@
 pub mod compiler;
 pub mod vm;
+pub mod lines;
+pub mod loxc;
+pub mod disasm;
 
 use super::*;
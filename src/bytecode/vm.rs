@@ -1,250 +1,857 @@
 use super::lox_type::*;
-
-static mut idx: usize = 0;
+use super::err::{RuntimeError,TypeError};
+use super::compiler::{Operation,Bytecode};
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use std::io::{self,Write};
 
 macro_rules! read {
-    ($b:expr) => {
+    ($vm:expr) => {
         {
-            unsafe { idx = idx + 1 };
-            unsafe { $b[idx - 1] }
+            match $vm.next_byte() {
+                Some(b) => b,
+                None => return Err($vm.error("Unexpected end of bytecode".to_string()))
+            }
         }
     }
 }
 
 macro_rules! read_u8 {
-    ($b:expr) => {
+    ($vm:expr) => {
         {
-            u8::from_le_bytes([read!($b)])
+            u8::from_le_bytes([read!($vm)])
         }
     }
 }
 
 macro_rules! read_u16 {
-    ($b:expr) => {
+    ($vm:expr) => {
         {
-            u16::from_le_bytes([read!($b),read!($b)])
+            u16::from_le_bytes([read!($vm),read!($vm)])
         }
     }
 }
 
-type VmRes = LoxType;
+type VmRes = Result<LoxType,RuntimeError>;
+
+// Pops the right operand, then the left one, and pushes what they make.
+macro_rules! binary {
+    ($vm:expr,$a:ident,$b:ident => $e:expr) => {
+        {
+            let $b = $vm.pop()?;
+            let $a = $vm.pop()?;
+            $vm.stack.push($e);
+        }
+    }
+}
 
-macro_rules! op {
-    ($f:expr) => {
-        $f
+// Globals are found by name when the code runs, so a function can use globals
+// declared after it. Each module has a table of its own, so its globals don't
+// clash with the importer's.
+type Globals = HashMap<Rc<str>,LoxType>;
+
+// Deep enough for any sane recursion, calls don't recurse natively but each
+// frame keeps its locals on the stack.
+const FRAMES_MAX: usize = 256;
+
+// One per function call, plus one at the bottom for the script itself.
+#[derive(Debug)]
+struct Frame {
+    closure: Option<Rc<Closure>>, // None for the script
+    ip: usize,
+    base: usize, // slot 0 of the call's locals
+    start: usize, // where the callee was, the result replaces everything from here
+    init: bool, // an initializer called through its class, which returns the instance
+    module: bool // running a module's top-level code
+}
+
+impl Frame {
+    fn script() -> Frame {
+        Frame {
+            closure: None,
+            ip: 0,
+            base: 0,
+            start: 0,
+            init: false,
+            module: false
+        }
     }
 }
 
-#[derive(Debug,Clone)]
-pub struct Var {
-    idx: u8,
-    v: LoxType
+// Everything a running program needs, so several programs can run side by
+// side without stepping on each other.
+pub struct Vm {
+    script: Bytecode,
+    frames: Vec<Frame>,
+    current_op: usize,
+    stack: Vec<LoxType>, // the locals of every call in slots, with the values being worked on above them
+    globals: Globals, // the main program's
+    names: HashSet<Rc<str>>, // interned global names, shared by every table
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    last: Option<LoxType>, // the value most recently popped, what an expression statement evaluated to
+    modules: HashMap<Rc<str>,Globals>, // globals of the modules that have been run
+    out: Box<dyn Write> // where print goes, stdout unless told otherwise
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("frames",&self.frames)
+            .field("stack",&self.stack)
+            .field("globals",&self.globals)
+            .finish()
+    }
 }
 
-impl Var {
-    pub fn new(l: u8,v:LoxType) -> Var {
-        Var {
-            idx: l,
-            v: v
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm {
+            script: Bytecode::default(),
+            frames: vec![Frame::script()],
+            current_op: 0,
+            stack: Vec::new(),
+            globals: Globals::new(),
+            names: HashSet::new(),
+            open_upvalues: Vec::new(),
+            last: None,
+            modules: HashMap::new(),
+            out: Box::new(io::stdout())
         }
     }
 }
 
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::default()
+    }
 
-// new index, value
-const OPS: [fn(v: &Vec<u8>, constants: &Vec<LoxType>) -> VmRes; 24] = [
-    op!(return_op),
-    op!(constant_op),
-    op!(long_constant_op),
-    op!(add_op),
-    op!(sub_op),
-    op!(mul_op),
-    op!(div_op),
-    op!(not_op),
-    op!(and_op),
-    op!(or_op),
-    op!(equals_op),
-    op!(greater_op),
-    op!(greater_equal_op),
-    op!(lesser_op),
-    op!(lesser_equal_op),
-    op!(var_op),
-    op!(assign_op),
-    op!(pop_op),
-    op!(get_op),
-    op!(jump_back_if_true_op),
-    op!(jump_back_if_false_op),
-    op!(jump_if_true_op),
-    op!(jump_if_false_op),
-    op!(not_equals_op)
-];
-
-static mut stack: Vec<Var> = Vec::new();
-
-fn stack_lookup(_idx: u8) -> Option<usize> {
-    unsafe { stack.iter().position(|x| x.idx == _idx) }
-}
+    // Sends print's output somewhere other than stdout, e.g. a buffer to check
+    // what a program printed.
+    pub fn with_output(out: Box<dyn Write>) -> Vm {
+        Vm {
+            out,
+            ..Vm::default()
+        }
+    }
 
-pub fn interpret(bytes: Vec<u8>,constants: Vec<LoxType>) {
-    unsafe {
-        while idx < bytes.len() {
-            do_op(&bytes,&constants);
+    pub fn set_output(&mut self,out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    // Appends code compiled from the same chunk as the code already loaded,
+    // the chunk's constants only ever grow so only the new ones are added.
+    pub fn load(&mut self,b: &Bytecode) {
+        self.script.code.extend_from_slice(&b.code);
+        self.script.lines.extend(&b.lines);
+        if b.constants.len() > self.script.constants.len() {
+            let len = self.script.constants.len();
+            self.script.constants.extend_from_slice(&b.constants[len..]);
         }
     }
-}
 
-fn do_op(bytes: &Vec<u8>, constants: &Vec<LoxType>) -> VmRes {
-    OPS[read_u8!(bytes) as usize](bytes,constants)
-}
+    // Runs from wherever the previous call stopped, so code loaded afterwards
+    // (e.g. by the repl) sees the globals declared before it.
+    // Returns the value of the last expression statement.
+    pub fn interpret(&mut self) -> VmRes {
+        self.last = None;
+        match self.run() {
+            Ok(()) => Ok(self.last.take().unwrap_or(LoxType::Nil)),
+            Err(e) => {
+                self.unwind();
+                Err(e)
+            }
+        }
+    }
 
-fn return_op(bytes: &Vec<u8>, constants: &Vec<LoxType>) -> VmRes {
-    LoxType::Nil
-}
+    // Skips whatever is left of the loaded code, used to carry on after a
+    // program failed halfway through.
+    pub fn skip_to_end(&mut self) {
+        self.unwind();
+        self.frames[0].ip = self.script.code.len();
+    }
 
-fn constant_op(b: &Vec<u8>, c: &Vec<LoxType>) -> VmRes {
-    c[read_u8!(b) as usize].into_owned()
-}
+    // Drops the calls that were running when an error stopped the program, and
+    // whatever they left on the stack. Modules that didn't finish running are
+    // forgotten, so importing them again runs them from the start.
+    fn unwind(&mut self) {
+        for f in self.frames.drain(1..).filter(|f| f.module) {
+            if let Some(file) = f.closure.as_ref().and_then(|c| c.function.file.as_ref()) {
+                self.modules.remove(file);
+            }
+        }
+        self.truncate_stack(0);
+    }
 
-fn long_constant_op(b: &Vec<u8>, c: &Vec<LoxType>) -> VmRes{
-    c[read_u16!(b) as usize].into_owned()
-}
+    fn frame(&self) -> &Frame {
+        &self.frames[self.frames.len() - 1]
+    }
 
-fn add_op(b: &Vec<u8>, c: &Vec<LoxType>) -> VmRes {
-    do_op(b,c) + do_op(b,c)
-}
+    fn bytecode(&self) -> &Bytecode {
+        match self.frame().closure {
+            Some(ref c) => &c.function.bytecode,
+            None => &self.script
+        }
+    }
 
-fn sub_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    do_op(b,c) - do_op(b,c)
-}
+    fn next_byte(&mut self) -> Option<u8> {
+        let frame = self.frames.last_mut()?;
+        let code = match frame.closure {
+            Some(ref c) => &c.function.bytecode.code,
+            None => &self.script.code
+        };
+        let b = code.get(frame.ip).copied();
+        if b.is_some() {
+            frame.ip += 1;
+        }
+        b
+    }
 
-fn mul_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    do_op(b,c) * do_op(b,c)
-}
+    // The operand of ops that have a long variant, which takes two bytes
+    // instead of one.
+    fn index(&mut self,op: Operation) -> Result<usize,RuntimeError> {
+        match op {
+            Operation::LongDefineGlobal | Operation::LongSetGlobal | Operation::LongGetGlobal |
+            Operation::LongGetLocal | Operation::LongSetLocal => Ok(read_u16!(self) as usize),
+            _ => Ok(read_u8!(self) as usize)
+        }
+    }
 
-fn div_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    do_op(b,c) / do_op(b,c)
-}
+    // Jumps take two bytes, long jumps four.
+    fn jump_offset(&mut self,op: Operation) -> Result<usize,RuntimeError> {
+        match op {
+            Operation::LongJump | Operation::LongJumpBack | Operation::LongJumpIfTrue | Operation::LongJumpIfFalse |
+            Operation::LongAnd | Operation::LongOr => {
+                Ok(u32::from_le_bytes([read!(self),read!(self),read!(self),read!(self)]) as usize)
+            },
+            _ => Ok(read_u16!(self) as usize)
+        }
+    }
 
-fn not_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    !do_op(b,c)
-}
+    // Operators used on the wrong types are runtime errors.
+    fn checked(&self,res: Result<LoxType,TypeError>) -> VmRes {
+        res.map_err(|e| self.error(e.msg))
+    }
 
-fn and_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-            .into()
-        &&
-        do_op(b,c)
-            .into()
-    )
-}
+    // Whether `a` and `b` are ordered the way `accept` takes.
+    fn compare(&self,a: &LoxType,b: &LoxType,op: &str,accept: fn(Ordering) -> bool) -> VmRes {
+        match a.compare(b,op) {
+            Ok(order) => Ok(LoxType::Boolean(order.is_some_and(accept))),
+            Err(e) => Err(self.error(e.msg))
+        }
+    }
 
-fn or_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-            .into()
-        ||
-        do_op(b,c)
-            .into()
-    )
-}
+    fn pop(&mut self) -> VmRes {
+        match self.stack.pop() {
+            Some(v) => Ok(v),
+            None => Err(self.error("Stack underflow".to_string()))
+        }
+    }
 
-fn equals_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        ==
-        do_op(b,c)
-    )
+    fn peek(&self) -> VmRes {
+        match self.stack.last() {
+            Some(v) => Ok(v.clone()),
+            None => Err(self.error("Stack underflow".to_string()))
+        }
+    }
 
-}
+    // Every op takes its operands off the stack and pushes its result, so the
+    // code is run one op after the other, calls included, until the script
+    // runs out.
+    fn run(&mut self) -> Result<(),RuntimeError> {
+        loop {
+            self.current_op = self.frame().ip;
+            let byte = match self.next_byte() {
+                Some(b) => b,
+                None if self.frames.len() == 1 => return Ok(()),
+                // falling off the end of a function returns nil
+                None => {
+                    self.return_from_call(LoxType::Nil)?;
+                    continue;
+                }
+            };
+            let op = match Operation::from_byte(byte) {
+                Some(op) => op,
+                None => return Err(self.error(format!("Unknown opcode {}",byte)))
+            };
+            match op {
+                Operation::Return => {
+                    let v = self.pop()?;
+                    // a return outside of any function ends the script
+                    if self.frames.len() == 1 {
+                        self.skip_to_end();
+                        return Ok(());
+                    }
+                    self.return_from_call(v)?;
+                },
+                Operation::Constant => {
+                    let idx = read_u8!(self) as usize;
+                    let v = self.constant(idx)?;
+                    self.stack.push(v);
+                },
+                Operation::LongConstant => {
+                    let idx = read_u16!(self) as usize;
+                    let v = self.constant(idx)?;
+                    self.stack.push(v);
+                },
+                Operation::Add => binary!(self,a,b => self.checked(a + b)?),
+                Operation::Subtract => binary!(self,a,b => self.checked(a - b)?),
+                Operation::Multiply => binary!(self,a,b => self.checked(a * b)?),
+                Operation::Divide => binary!(self,a,b => self.checked(a / b)?),
+                Operation::Negate => {
+                    let v = self.pop()?;
+                    let v = self.checked(-v)?;
+                    self.stack.push(v);
+                },
+                Operation::Not => {
+                    let v = self.pop()?;
+                    self.stack.push(!v);
+                },
+                Operation::Equals => binary!(self,a,b => LoxType::Boolean(a == b)),
+                Operation::NotEquals => binary!(self,a,b => LoxType::Boolean(a != b)),
+                Operation::Greater => binary!(self,a,b => self.compare(&a,&b,">",Ordering::is_gt)?),
+                Operation::GreaterEqual => binary!(self,a,b => self.compare(&a,&b,">=",Ordering::is_ge)?),
+                Operation::Lesser => binary!(self,a,b => self.compare(&a,&b,"<",Ordering::is_lt)?),
+                Operation::LesserEqual => binary!(self,a,b => self.compare(&a,&b,"<=",Ordering::is_le)?),
+                // declaring a global that already exists replaces it, e.g. in the repl
+                Operation::DefineGlobal | Operation::LongDefineGlobal => {
+                    let idx = self.index(op)?;
+                    let name = self.name(idx)?;
+                    let v = self.pop()?;
+                    let name = self.intern(&name);
+                    self.globals_mut().insert(name,v);
+                },
+                Operation::SetGlobal | Operation::LongSetGlobal => {
+                    let idx = self.index(op)?;
+                    let v = self.peek()?;
+                    if !self.assign_global(idx,v) {
+                        return Err(self.error(format!("Undefined variable '{}'",self.name(idx)?)));
+                    }
+                },
+                Operation::GetGlobal | Operation::LongGetGlobal => {
+                    let idx = self.index(op)?;
+                    let v = match self.bytecode().constants.get(idx) {
+                        Some(&LoxType::String(ref name)) => self.globals().and_then(|g| g.get(name.as_str())).cloned(),
+                        _ => return Err(self.error(format!("Constant {} is not a variable name",idx)))
+                    };
+                    match v {
+                        Some(v) => self.stack.push(v),
+                        None => return Err(self.error(format!("Undefined variable '{}'",self.name(idx)?)))
+                    }
+                },
+                Operation::Local => self.stack.push(LoxType::Nil),
+                Operation::GetLocal | Operation::LongGetLocal => {
+                    let slot = self.index(op)?;
+                    let i = self.local(slot)?;
+                    let v = self.stack[i].clone();
+                    self.stack.push(v);
+                },
+                Operation::SetLocal | Operation::LongSetLocal => {
+                    let slot = self.index(op)?;
+                    let v = self.peek()?;
+                    let i = self.local(slot)?;
+                    self.stack[i] = v;
+                },
+                Operation::Pop => {
+                    let amt = read_u8!(self) as usize;
+                    // a call can only pop what it pushed
+                    let available = self.stack.len().saturating_sub(self.frame().base);
+                    if amt > available {
+                        return Err(self.error(format!("Stack underflow, popping {} of {} values",amt,available)));
+                    }
+                    let len = self.stack.len() - amt;
+                    self.last = self.stack.last().cloned();
+                    self.truncate_stack(len);
+                },
+                Operation::Jump | Operation::LongJump => {
+                    let offset = self.jump_offset(op)?;
+                    self.jump(self.frame().ip + offset)?;
+                },
+                Operation::JumpBack | Operation::LongJumpBack => {
+                    let offset = self.jump_offset(op)?;
+                    self.jump_back(offset)?;
+                },
+                Operation::JumpIfTrue | Operation::JumpIfFalse | Operation::LongJumpIfTrue | Operation::LongJumpIfFalse => {
+                    let offset = self.jump_offset(op)?;
+                    let c = bool::from(self.pop()?);
+                    if c == matches!(op,Operation::JumpIfTrue | Operation::LongJumpIfTrue) {
+                        self.jump(self.frame().ip + offset)?;
+                    }
+                },
+                // the left operand is the result if it decides it
+                Operation::And | Operation::Or | Operation::LongAnd | Operation::LongOr => {
+                    let offset = self.jump_offset(op)?;
+                    if bool::from(self.peek()?) == matches!(op,Operation::Or | Operation::LongOr) {
+                        self.jump(self.frame().ip + offset)?;
+                    } else {
+                        self.stack.pop();
+                    }
+                },
+                Operation::Call => {
+                    let argc = read_u8!(self) as usize;
+                    self.call_value(argc)?;
+                },
+                Operation::Closure => {
+                    let closure = self.closure()?;
+                    self.stack.push(closure);
+                },
+                Operation::GetUpvalue => {
+                    let idx = read_u8!(self) as usize;
+                    let u = self.upvalue(idx)?;
+                    let v = match *u.borrow() {
+                        Upvalue::Open(slot) => match self.stack.get(slot) {
+                            Some(v) => v.clone(),
+                            None => return Err(self.error(format!("Upvalue slot {} out of range",slot)))
+                        },
+                        Upvalue::Closed(ref v) => v.clone()
+                    };
+                    self.stack.push(v);
+                },
+                Operation::SetUpvalue => {
+                    let idx = read_u8!(self) as usize;
+                    let v = self.peek()?;
+                    let u = self.upvalue(idx)?;
+                    let mut u = u.borrow_mut();
+                    match *u {
+                        Upvalue::Open(slot) => match self.stack.get_mut(slot) {
+                            Some(s) => *s = v,
+                            None => return Err(self.error(format!("Upvalue slot {} out of range",slot)))
+                        },
+                        Upvalue::Closed(ref mut c) => *c = v
+                    }
+                },
+                Operation::Class => {
+                    let idx = read_u16!(self) as usize;
+                    let name = self.name(idx)?;
+                    self.stack.push(LoxType::Class(Rc::new(Class::new(name))));
+                },
+                Operation::Inherit => {
+                    let superclass = self.pop()?;
+                    let class = self.pop()?;
+                    match (class,superclass) {
+                        (LoxType::Class(c),LoxType::Class(s)) => {
+                            let methods = s.methods.borrow().clone();
+                            c.methods.borrow_mut().extend(methods);
+                        },
+                        (_,other) => return Err(self.error(format!("Superclass must be a class, not '{}'",other)))
+                    }
+                },
+                Operation::Method => {
+                    let idx = read_u16!(self) as usize;
+                    let name = self.name(idx)?;
+                    let method = self.pop()?;
+                    let class = self.pop()?;
+                    match (class,method) {
+                        (LoxType::Class(c),LoxType::Closure(m)) => { c.methods.borrow_mut().insert(name,m); },
+                        _ => return Err(self.error(format!("Can't define method '{}' outside of a class",name)))
+                    }
+                },
+                Operation::GetProperty => {
+                    let idx = read_u16!(self) as usize;
+                    let name = self.name(idx)?;
+                    let object = self.pop()?;
+                    let v = self.get_property(object,name)?;
+                    self.stack.push(v);
+                },
+                Operation::SetProperty => {
+                    let idx = read_u16!(self) as usize;
+                    let name = self.name(idx)?;
+                    let v = self.pop()?;
+                    match self.pop()? {
+                        LoxType::Instance(ref i) => { i.fields.borrow_mut().insert(name,v.clone()); },
+                        LoxType::Module(ref m) => return Err(self.error(format!("Can't assign to '{}' of module '{}'",name,m.file))),
+                        other => return Err(self.error(format!("Only instances have fields, not '{}'",other)))
+                    }
+                    self.stack.push(v);
+                },
+                Operation::GetSuper => {
+                    let idx = read_u16!(self) as usize;
+                    let name = self.name(idx)?;
+                    let superclass = match self.pop()? {
+                        LoxType::Class(c) => c,
+                        other => return Err(self.error(format!("Superclass must be a class, not '{}'",other)))
+                    };
+                    let this = self.pop()?;
+                    match superclass.find_method(&name) {
+                        Some(method) => self.stack.push(LoxType::BoundMethod(Rc::new(BoundMethod { receiver: this, method }))),
+                        None => return Err(self.error(format!("Undefined property '{}'",name)))
+                    }
+                },
+                Operation::Print => {
+                    let v = self.pop()?;
+                    if let Err(e) = writeln!(self.out,"{}",v) {
+                        return Err(self.error(format!("Failed to write output: {}",e)));
+                    }
+                },
+                Operation::Import => self.run_module()?,
+                Operation::ImportModule => {
+                    let module = self.module()?;
+                    self.stack.push(module);
+                },
+                Operation::ImportNames => {
+                    let module = self.pop()?;
+                    self.import_names(module)?;
+                },
+                Operation::Byte(_) | Operation::Short(_) | Operation::Word(_) | Operation::Line(_)
+                    | Operation::Break | Operation::Continue => unreachable!()
+            }
+        }
+    }
 
-fn not_equals_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        !=
-        do_op(b,c)
-    )
-}
+    // Calls whatever is below the `argc` arguments on top of the stack.
+    fn call_value(&mut self,argc: usize) -> Result<(),RuntimeError> {
+        if argc >= self.stack.len() {
+            return Err(self.error("Stack underflow".to_string()));
+        }
+        let start = self.stack.len() - argc - 1;
+        match self.stack[start].clone() {
+            LoxType::Closure(c) => self.call(c,argc,start + 1,start,false),
+            // the receiver takes the callee's place, as `this` in slot 0
+            // calling init again gives the instance too
+            LoxType::BoundMethod(b) => {
+                self.stack[start] = b.receiver.clone();
+                let init = b.method.function.name.as_deref() == Some("init");
+                self.call(b.method.clone(),argc,start,start,init)
+            },
+            LoxType::Class(class) => {
+                self.stack[start] = LoxType::Instance(Rc::new(Instance::new(class.clone())));
+                match class.find_method("init") {
+                    Some(init) => self.call(init,argc,start,start,true),
+                    None if argc > 0 => Err(self.error(format!("{} expected 0 arguments but got {}",class.name,argc))),
+                    None => Ok(())
+                }
+            },
+            other => Err(self.error(format!("Can only call functions and classes, not '{}'",other)))
+        }
+    }
 
-fn greater_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        >
-        do_op(b,c)
-    )
-}
+    // Runs a function's body in a new frame, its arguments are already on the
+    // stack as its first locals, after `this` for methods.
+    fn call(&mut self,c: Rc<Closure>,argc: usize,base: usize,start: usize,init: bool) -> Result<(),RuntimeError> {
+        if argc != c.function.arity() {
+            return Err(self.error(format!("{} expected {} arguments but got {}",c.function,c.function.arity(),argc)));
+        }
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(self.error("Stack overflow".to_string()));
+        }
+        self.frames.push(Frame { closure: Some(c), ip: 0, base, start, init, module: false });
+        Ok(())
+    }
 
-fn greater_equal_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        >=
-        do_op(b,c)
-    )
-}
+    // Ends the running call, its result replaces the callee and its arguments.
+    // Whatever init returns, calling the class gives the new instance.
+    fn return_from_call(&mut self,v: LoxType) -> Result<(),RuntimeError> {
+        let v = if self.frame().init {
+            match self.stack.get(self.frame().base) {
+                Some(this) => this.clone(),
+                None => return Err(self.error("Stack underflow, the initializer's instance is gone".to_string()))
+            }
+        } else {
+            v
+        };
+        let frame = self.frames.pop().unwrap();
+        self.truncate_stack(frame.start);
+        if !frame.module {
+            self.stack.push(v);
+        }
+        Ok(())
+    }
 
-fn lesser_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        <
-        do_op(b,c)
-    )
-}
+    fn closure(&mut self) -> VmRes {
+        let idx = read_u16!(self) as usize;
+        let function = match self.constant(idx)? {
+            LoxType::Function(f) => f,
+            other => return Err(self.error(format!("Expected a function constant, found '{}'",other)))
+        };
+        let mut upvalues = Vec::with_capacity(function.upvalue_count);
+        for _ in 0..function.upvalue_count {
+            let is_local = read_u8!(self) != 0;
+            let index = read_u16!(self) as usize;
+            upvalues.push(if is_local {
+                // a local function captures the slot it's about to be put in
+                let slot = self.frame().base + index;
+                if slot > self.stack.len() {
+                    return Err(self.error(format!("Local slot {} out of range",index)));
+                }
+                self.capture(slot)
+            } else {
+                self.upvalue(index)?
+            });
+        }
+        Ok(LoxType::Closure(Rc::new(Closure { function, upvalues })))
+    }
 
-fn lesser_equal_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    LoxType::Boolean(
-        do_op(b,c)
-        <=
-        do_op(b,c)
-    )
-}
+    // Fields shadow methods of the same name.
+    fn get_property(&self,object: LoxType,name: String) -> VmRes {
+        let instance = match object {
+            LoxType::Instance(ref i) => i.clone(),
+            LoxType::Module(ref m) => {
+                if !m.exports.contains(&name) {
+                    return Err(self.error(format!("Module '{}' doesn't export '{}'",m.file,name)));
+                }
+                return match self.module_global(&m.file,&name) {
+                    Some(v) => Ok(v),
+                    None => Err(self.error(format!("Module '{}' didn't define '{}'",m.file,name)))
+                };
+            },
+            other => return Err(self.error(format!("Only instances have properties, not '{}'",other)))
+        };
+        if let Some(v) = instance.fields.borrow().get(&name) {
+            return Ok(v.clone());
+        }
+        match instance.class.find_method(&name) {
+            Some(method) => Ok(LoxType::BoundMethod(Rc::new(BoundMethod { receiver: object, method }))),
+            None => Err(self.error(format!("Undefined property '{}'",name)))
+        }
+    }
 
-fn var_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    let _idx = read_u8!(b);
-    unsafe { stack.push(Var::new(_idx,LoxType::Nil)) };
-    LoxType::Nil
-}
+    fn module_function(&mut self) -> Result<(Rc<Function>,Rc<str>),RuntimeError> {
+        let idx = read_u16!(self) as usize;
+        let module = match self.constant(idx)? {
+            LoxType::Function(f) => f,
+            other => return Err(self.error(format!("Expected a module, found '{}'",other)))
+        };
+        match module.file {
+            Some(ref f) => Ok((module.clone(),f.clone())),
+            None => Err(self.error(format!("{} is not a module",module)))
+        }
+    }
 
-fn assign_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    let _idx = read_u8!(b);
-    unsafe { stack[stack_lookup(_idx).unwrap()].v = do_op(b,c); }
-    LoxType::Nil
-}
+    // Starts running the module's top-level code, which declares its globals,
+    // unless it has run already. Each module only runs once however many times
+    // it's imported.
+    fn run_module(&mut self) -> Result<(),RuntimeError> {
+        let (f,file) = self.module_function()?;
+        if self.modules.contains_key(&file) {
+            return Ok(());
+        }
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(self.error("Stack overflow".to_string()));
+        }
+        self.modules.insert(file,Globals::new());
+        let closure = Rc::new(Closure { function: f, upvalues: Vec::new() });
+        let base = self.stack.len();
+        self.frames.push(Frame { closure: Some(closure), ip: 0, base, start: base, init: false, module: true });
+        Ok(())
+    }
 
-fn pop_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    let amt = read_u8!(b);
-    unsafe { stack.truncate(stack.len() - amt as usize) };
-    LoxType::Nil
-}
+    // A module that has been run, with the names it exports.
+    fn module(&mut self) -> VmRes {
+        let (_,file) = self.module_function()?;
+        let count = read_u16!(self);
+        let mut exports = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let idx = read_u16!(self) as usize;
+            exports.push(self.name(idx)?);
+        }
+        Ok(LoxType::Module(Rc::new(Module { file, exports })))
+    }
 
-fn get_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    let _idx = read_u8!(b);
-    unsafe { (&stack[stack_lookup(_idx).unwrap()].v).into_owned() }
-}
+    // Copies what the module exports into the importer's globals.
+    fn import_names(&mut self,module: LoxType) -> Result<(),RuntimeError> {
+        let m = match module {
+            LoxType::Module(m) => m,
+            other => return Err(self.error(format!("Expected a module, found '{}'",other)))
+        };
+        for name in &m.exports {
+            let v = match self.module_global(&m.file,name) {
+                Some(v) => v,
+                None => return Err(self.error(format!("Module '{}' didn't define '{}'",m.file,name)))
+            };
+            let name = self.intern(name);
+            self.globals_mut().insert(name,v);
+        }
+        Ok(())
+    }
 
-fn jump_if_true_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    if bool::from(do_op(b,c)) {
-        unsafe { idx += read_u8!(b) as usize }
+    // Drops variables off the stack, closures that captured them keep their
+    // last values.
+    fn truncate_stack(&mut self,len: usize) {
+        if self.open_upvalues.is_empty() {
+            self.stack.truncate(len);
+            return;
+        }
+        let stack = &self.stack;
+        self.open_upvalues.retain(|u| {
+            let mut u = u.borrow_mut();
+            match *u {
+                // only broken bytecode leaves a slot behind that's gone already
+                Upvalue::Open(slot) if slot >= len => {
+                    *u = Upvalue::Closed(stack.get(slot).cloned().unwrap_or(LoxType::Nil));
+                    false
+                },
+                _ => true
+            }
+        });
+        self.stack.truncate(len);
     }
-    LoxType::Nil
-}
 
-fn jump_if_false_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    if !bool::from(do_op(b,c)) {
-        unsafe { idx += read_u8!(b) as usize }
+    // Closures capturing the same variable share its upvalue, so they see each
+    // other's assignments.
+    fn capture(&mut self,slot: usize) -> Rc<RefCell<Upvalue>> {
+        for u in &self.open_upvalues {
+            if let Upvalue::Open(s) = *u.borrow() {
+                if s == slot {
+                    return u.clone();
+                }
+            }
+        }
+        let u = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(u.clone());
+        u
     }
-    LoxType::Nil
-}
 
-fn jump_back_if_true_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    if bool::from(do_op(b,c)) {
-        unsafe { idx -= read_u8!(b) as usize }
+    fn upvalue(&self,idx: usize) -> Result<Rc<RefCell<Upvalue>>,RuntimeError> {
+        match self.frame().closure {
+            Some(ref c) => match c.upvalues.get(idx) {
+                Some(u) => Ok(u.clone()),
+                None => Err(self.error(format!("Upvalue {} out of range",idx)))
+            },
+            None => Err(self.error("Upvalues can only be used inside functions".to_string()))
+        }
+    }
+
+    // Errors point at the innermost operation that's currently executing.
+    fn error(&self,msg: String) -> RuntimeError {
+        let b = self.bytecode();
+        let op = b.code.get(self.current_op).and_then(|b| Operation::from_byte(*b));
+        let mut e = RuntimeError::new(msg,op,self.current_op,b.lines.line_at(self.current_op));
+        e.file = self.namespace().map(|f| f.to_string());
+        let mut repeated = 0;
+        if self.frames.len() > 1 {
+            let calls = self.frames.iter().rev().enumerate().map(|(i,frame)| {
+                let (name,lines) = match frame.closure {
+                    Some(ref c) if frame.module => (format!("module {}",c.function.file.as_deref().unwrap_or("?")),&c.function.bytecode.lines),
+                    Some(ref c) => (c.function.to_string(),&c.function.bytecode.lines),
+                    None => ("script".to_string(),&self.script.lines)
+                };
+                // callers have moved past the call, their ip is still on its line
+                let offset = if i == 0 { self.current_op } else { frame.ip.saturating_sub(1) };
+                match lines.line_at(offset) {
+                    Some(l) => format!("in {} (line {})",name,l),
+                    None => format!("in {}",name)
+                }
+            });
+            // runaway recursion would otherwise list the same call hundreds of times
+            for call in calls {
+                match e.trace.last() {
+                    Some(last) if *last == call => repeated += 1,
+                    _ => {
+                        if repeated > 0 {
+                            e.trace.push(format!("... repeated {} more times",repeated));
+                            repeated = 0;
+                        }
+                        e.trace.push(call);
+                    }
+                }
+            }
+        }
+        e
+    }
+
+    fn constant(&self,idx: usize) -> VmRes {
+        match self.bytecode().constants.get(idx) {
+            Some(c) => Ok(c.clone()),
+            None => Err(self.error(format!("Constant {} out of range",idx)))
+        }
+    }
+
+    fn name(&self,idx: usize) -> Result<String,RuntimeError> {
+        match self.bytecode().constants.get(idx) {
+            Some(&LoxType::String(ref s)) => Ok(s.clone()),
+            _ => Err(self.error(format!("Constant {} is not a variable name",idx)))
+        }
+    }
+
+    // The module the running code comes from.
+    fn namespace(&self) -> Option<Rc<str>> {
+        self.frame().closure.as_ref().and_then(|c| c.function.file.clone())
+    }
+
+    fn intern(&mut self,name: &str) -> Rc<str> {
+        match self.names.get(name) {
+            Some(n) => n.clone(),
+            None => {
+                let n: Rc<str> = Rc::from(name);
+                self.names.insert(n.clone());
+                n
+            }
+        }
+    }
+
+    // The globals of the module the running code comes from.
+    fn globals(&self) -> Option<&Globals> {
+        match self.namespace() {
+            Some(ref file) => self.modules.get(file),
+            None => Some(&self.globals)
+        }
+    }
+
+    fn globals_mut(&mut self) -> &mut Globals {
+        match self.namespace() {
+            Some(file) => self.modules.entry(file).or_default(),
+            None => &mut self.globals
+        }
+    }
+
+    // Assigns the global named by constant `idx` if it's been declared, the
+    // name is looked up where it is rather than copied out of the constants.
+    fn assign_global(&mut self,idx: usize,v: LoxType) -> bool {
+        let (constants,file) = match self.frames[self.frames.len() - 1].closure {
+            Some(ref c) => (&c.function.bytecode.constants,c.function.file.as_ref()),
+            None => (&self.script.constants,None)
+        };
+        let globals = match file {
+            Some(f) => self.modules.get_mut(f),
+            None => Some(&mut self.globals)
+        };
+        match (constants.get(idx),globals) {
+            (Some(&LoxType::String(ref name)),Some(g)) => match g.get_mut(name.as_str()) {
+                Some(g) => {
+                    *g = v;
+                    true
+                },
+                None => false
+            },
+            _ => false
+        }
     }
-    LoxType::Nil
-}
 
-fn jump_back_if_false_op(b: &Vec<u8>,c: &Vec<LoxType>) -> VmRes {
-    if !bool::from(do_op(b,c)) {
-        unsafe { idx -= read_u8!(b) as usize }
+    // A global of an imported module, which has finished running by now.
+    fn module_global(&self,file: &Rc<str>,name: &str) -> Option<LoxType> {
+        self.modules.get(file).and_then(|g| g.get(name)).cloned()
     }
-    LoxType::Nil
+
+    // Slots count from the start of the current call's locals.
+    fn local(&self,slot: usize) -> Result<usize,RuntimeError> {
+        let i = self.frame().base + slot;
+        if i < self.stack.len() {
+            Ok(i)
+        } else {
+            Err(self.error(format!("Local slot {} out of range",slot)))
+        }
+    }
+
+    fn jump(&mut self,to: usize) -> Result<(),RuntimeError> {
+        if to > self.bytecode().code.len() {
+            return Err(self.error(format!("Jump to {:04} is past the end of the code",to)));
+        }
+        self.frames.last_mut().unwrap().ip = to;
+        Ok(())
+    }
+
+    fn jump_back(&mut self,offset: usize) -> Result<(),RuntimeError> {
+        match self.frame().ip.checked_sub(offset) {
+            Some(to) => self.jump(to),
+            None => Err(self.error(format!("Jump back by {} is before the start of the code",offset)))
+        }
+    }
+}
+
+pub fn interpret(b: &Bytecode) -> VmRes {
+    let mut vm = Vm::new();
+    vm.load(b);
+    vm.interpret()
 }
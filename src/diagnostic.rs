@@ -0,0 +1,234 @@
+use super::err::{LoxError,RuntimeError};
+use super::syntax::token::Span;
+use std::error::Error;
+use std::fmt::Write;
+use std::io::{self,IsTerminal};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+// Runtime errors only know which line they came from, everything else has an
+// exact span.
+#[derive(Debug,Clone,Copy)]
+pub enum Location {
+    Span(Span),
+    Line(i32)
+}
+
+#[derive(Debug,Clone)]
+pub struct Label {
+    pub span: Span,
+    pub msg: String
+}
+
+#[derive(Debug,Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub msg: String,
+    pub location: Option<Location>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Whether diagnostics written to stderr should be coloured.
+pub fn use_colour() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal()
+}
+
+struct Style {
+    colour: bool
+}
+
+impl Style {
+    fn paint(&self,code: &str,s: &str) -> String {
+        if self.colour {
+            format!("{}{}{}",code,s,RESET)
+        } else {
+            s.to_string()
+        }
+    }
+}
+
+// Byte range of the given 1-based line, without its line break.
+fn line_range(source: &str,line: i32) -> Option<(usize,usize)> {
+    let mut start = 0;
+    for (i,l) in source.split('\n').enumerate() {
+        if i as i32 + 1 == line {
+            return Some((start,start + l.trim_end_matches('\r').len()));
+        }
+        start += l.len() + 1;
+    }
+    None
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity,msg: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            msg,
+            location: None,
+            labels: Vec::new(),
+            notes: Vec::new()
+        }
+    }
+
+    pub fn error(msg: String) -> Diagnostic {
+        Diagnostic::new(Severity::Error,msg)
+    }
+
+    pub fn warning(msg: String) -> Diagnostic {
+        Diagnostic::new(Severity::Warning,msg)
+    }
+
+    pub fn at(mut self,location: Location) -> Diagnostic {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_label(mut self,span: Span,msg: String) -> Diagnostic {
+        self.labels.push(Label { span, msg });
+        self
+    }
+
+    pub fn with_note(mut self,note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    fn line(&self) -> Option<i32> {
+        match self.location {
+            Some(Location::Span(s)) => Some(s.line),
+            Some(Location::Line(l)) => Some(l),
+            None => None
+        }
+    }
+
+    /*
+    error: Expected expression
+     --> script.lox:3:12
+      |
+    3 | var c = 2 +;
+      |            ^
+      = note: ...
+    */
+    pub fn render(&self,source: &str,file: &str,colour: bool) -> String {
+        self.render_with(Some(source),file,colour)
+    }
+
+    // For when the source isn't around, e.g. with compiled files: the location
+    // but no snippet.
+    pub fn render_without_source(&self,file: &str,colour: bool) -> String {
+        self.render_with(None,file,colour)
+    }
+
+    fn render_with(&self,source: Option<&str>,file: &str,colour: bool) -> String {
+        let style = Style { colour };
+        let mut out = String::new();
+        let (title,accent) = match self.severity {
+            Severity::Error => ("error",RED),
+            Severity::Warning => ("warning",YELLOW)
+        };
+        let _ = writeln!(out,"{}{}",style.paint(accent,title),style.paint(BOLD,&format!(": {}",self.msg)));
+
+        let mut lines: Vec<i32> = self.labels.iter().map(|l| l.span.line).collect();
+        lines.extend(self.line());
+        let width = lines.iter().max().map(|l| l.to_string().len()).unwrap_or(1);
+        let gutter = style.paint(BLUE,&format!("{} |"," ".repeat(width)));
+
+        match self.location {
+            Some(Location::Span(s)) => { let _ = writeln!(out,"{}{} {}:{}:{}"," ".repeat(width),style.paint(BLUE,"-->"),file,s.line,s.column); },
+            Some(Location::Line(l)) => { let _ = writeln!(out,"{}{} {}:{}"," ".repeat(width),style.paint(BLUE,"-->"),file,l); },
+            None => { let _ = writeln!(out,"{}{} {}"," ".repeat(width),style.paint(BLUE,"-->"),file); }
+        }
+
+        // without the source there's nothing to point at
+        if let Some(source) = source {
+            // the primary location first, then secondary labels in source order
+            let mut snippets: Vec<(Span,&str,bool)> = Vec::new();
+            match self.location {
+                Some(Location::Span(s)) => snippets.push((s,"",true)),
+                Some(Location::Line(l)) => {
+                    if let Some((start,end)) = line_range(source,l) {
+                        let indent = source[start..end].len() - source[start..end].trim_start().len();
+                        snippets.push((Span { start: start + indent, end, line: l, column: 1 },"",true));
+                    }
+                },
+                None => ()
+            }
+            let mut labels: Vec<&Label> = self.labels.iter().collect();
+            labels.sort_by_key(|l| l.span.start);
+            snippets.extend(labels.iter().map(|l| (l.span,l.msg.as_str(),false)));
+
+            if !snippets.is_empty() {
+                let _ = writeln!(out,"{}",gutter);
+            }
+            for (span,msg,primary) in snippets {
+                let (start,end) = match line_range(source,span.line) {
+                    Some(r) => r,
+                    None => continue
+                };
+                let text = &source[start..end];
+                let _ = writeln!(out,"{} {}",style.paint(BLUE,&format!("{:>w$} |",span.line,w = width)),text);
+
+                // keep tabs so the markers line up with the text above them
+                let from = span.start.clamp(start,end);
+                let to = span.end.clamp(from,end);
+                let pad: String = source[start..from].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                let len = source[from..to].chars().count().max(1);
+                let marker = if primary { "^" } else { "-" }.repeat(len);
+                let marker = if msg.is_empty() { marker } else { format!("{} {}",marker,msg) };
+                let _ = writeln!(out,"{} {}{}",gutter,pad,style.paint(if primary { accent } else { BLUE },&marker));
+            }
+        }
+
+        for n in &self.notes {
+            let _ = writeln!(out,"{} {}"," ".repeat(width),style.paint(BOLD,&format!("= note: {}",n)));
+        }
+        out
+    }
+}
+
+impl From<&LoxError> for Diagnostic {
+    fn from(e: &LoxError) -> Diagnostic {
+        let d = Diagnostic::error(e.msg().to_string()).at(Location::Span(e.span));
+        let d = e.labels.iter().fold(d,|d,&(span,ref msg)| d.with_label(span,msg.clone()));
+        match e.source() {
+            Some(cause) => d.with_note(cause.to_string()),
+            None => d
+        }
+    }
+}
+
+impl Diagnostic {
+    // Warnings are found the way errors are, they just don't stop anything.
+    pub fn warning_from(e: &LoxError) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::from(e)
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(e: &RuntimeError) -> Diagnostic {
+        let d = Diagnostic::error(e.msg.clone());
+        let d = match e.line {
+            Some(l) => d.at(Location::Line(l)),
+            None => d
+        };
+        let d = match e.op {
+            Some(op) => d.with_note(format!("while executing {:?} at {:04}",op,e.offset)),
+            None => d.with_note(format!("at {:04}",e.offset))
+        };
+        e.trace.iter().fold(d,|d,t| d.with_note(t.clone()))
+    }
+}
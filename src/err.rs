@@ -1,12 +1,17 @@
 use std::io;
 use std::error::Error;
 use std::fmt;
+use super::bytecode::compiler::Operation;
+use super::syntax::token::Span;
+use super::module::SourceFile;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct LoxError {
-    line: i32,
+    pub span: Span,
     err: String,
-    lower: Option<io::Error>
+    lower: Option<io::Error>,
+    pub labels: Vec<(Span,String)> // other places in the source that explain it
 }
 
 impl Error for LoxError {
@@ -14,9 +19,9 @@ impl Error for LoxError {
         &self.err
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         if let Some(ref err) = self.lower {
-            Some(err as &Error)
+            Some(err as &(dyn Error + 'static))
         } else {
             None
         }
@@ -25,24 +30,111 @@ impl Error for LoxError {
 
 impl fmt::Display for LoxError {
     fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f,"Error: {} in line {}",&self.err,&self.line)
+        writeln!(f,"Error: {} in line {}, column {}",&self.err,self.span.line,self.span.column)
     }
 }
 
 impl LoxError {
-    pub fn new(s: String,l: i32) -> LoxError {
+    pub fn msg(&self) -> &str {
+        &self.err
+    }
+
+    pub fn new(s: String,span: Span) -> LoxError {
         LoxError {
-            line: l,
+            span,
             err: s,
-            lower: None
+            lower: None,
+            labels: Vec::new()
         }
     }
 
-    pub fn with_lower(s: String,l: i32,e: io::Error) -> LoxError {
+    pub fn with_lower(s: String,span: Span,e: io::Error) -> LoxError {
         LoxError {
-            line: l,
+            span,
             err: s,
-            lower: Some(e)
+            lower: Some(e),
+            labels: Vec::new()
+        }
+    }
+
+    pub fn with_label(mut self,span: Span,msg: String) -> LoxError {
+        self.labels.push((span,msg));
+        self
+    }
+}
+
+// An error found while compiling. `file` is None when it's in the program
+// being compiled, whose source the caller already has, rather than in a module
+// it imports.
+#[derive(Debug)]
+pub struct CompileError {
+    pub file: Option<Rc<SourceFile>>,
+    pub error: LoxError
+}
+
+// An operator applied to values of types it doesn't work on.
+#[derive(Debug,Clone)]
+pub struct TypeError {
+    pub msg: String
+}
+
+impl Error for TypeError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}",&self.msg)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct RuntimeError {
+    pub msg: String,
+    pub op: Option<Operation>, // None if the opcode itself couldn't be decoded
+    pub offset: usize,
+    pub line: Option<i32>,
+    pub file: Option<String>, // the module the error happened in, None for the main program
+    pub trace: Vec<String> // innermost call first, empty outside of functions
+}
+
+impl Error for RuntimeError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"Runtime error: {}",&self.msg)?;
+        if let Some(line) = self.line {
+            write!(f," in line {}",line)?;
+        }
+        if let Some(ref file) = self.file {
+            write!(f," of {}",file)?;
+        }
+        match self.op {
+            Some(op) => writeln!(f," ({:?} at {:04})",op,self.offset)?,
+            None => writeln!(f," (at {:04})",self.offset)?
+        }
+        for t in &self.trace {
+            writeln!(f,"  {}",t)?;
+        }
+        Ok(())
+    }
+}
+
+impl RuntimeError {
+    pub fn new(msg: String,op: Option<Operation>,offset: usize,line: Option<i32>) -> RuntimeError {
+        RuntimeError {
+            msg,
+            op,
+            offset,
+            line,
+            file: None,
+            trace: Vec::new()
         }
     }
 }
@@ -0,0 +1,14 @@
+// `match x { &Enum::Variant(ref v) => .. }` is the house style
+#![allow(clippy::match_ref_pats,clippy::needless_borrowed_reference)]
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod lox_type;
+pub mod syntax;
+pub mod err;
+pub mod bytecode;
+pub mod diagnostic;
+pub mod module;
+
+use syntax::*;
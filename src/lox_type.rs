@@ -1,10 +1,13 @@
-use super::err::LoxError;
-//use super::{Expr,Callable};
+use super::err::{LoxError,TypeError};
+use super::syntax::token::Span;
+use super::bytecode::compiler::Bytecode;
 use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::cmp::{Ordering,PartialOrd,PartialEq};
-use std::boxed::Box;
-use std::ops::{Sub,Add,Mul,Div,Not};
+use std::cmp::{Ordering,PartialEq};
+use std::ops::{Sub,Add,Mul,Div,Neg,Not};
 
 #[derive(Debug,Clone)]
 pub enum LoxType {
@@ -12,16 +15,143 @@ pub enum LoxType {
     Number(f64),
     Nil,
     Boolean(bool),
-    //Callable(Box<Callable>)
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    Module(Rc<Module>)
 }
 
 impl LoxType {
-    pub fn into_owned(&self) -> LoxType {
+    // What kind of value it is, for error messages.
+    pub fn type_name(&self) -> &'static str {
         match self {
-            &LoxType::Number(ref n) => LoxType::Number(*n),
-            &LoxType::Boolean(ref b) => LoxType::Boolean(*b),
-            &LoxType::String(ref s) => LoxType::String(s.clone()),
-            _ => panic!("no")
+            &LoxType::String(_) => "string",
+            &LoxType::Number(_) => "number",
+            &LoxType::Nil => "nil",
+            &LoxType::Boolean(_) => "boolean",
+            &LoxType::Function(_) | &LoxType::Closure(_) | &LoxType::BoundMethod(_) => "function",
+            &LoxType::Class(_) => "class",
+            &LoxType::Instance(_) => "instance",
+            &LoxType::Module(_) => "module"
+        }
+    }
+
+    // Only numbers and strings have an order, `op` is the comparison it's for.
+    // None when there's no order between the two, with NaN.
+    pub fn compare(&self,other: &LoxType,op: &str) -> Result<Option<Ordering>,TypeError> {
+        match (self,other) {
+            (&LoxType::String(ref s),&LoxType::String(ref o)) => Ok(s.partial_cmp(o)),
+            (&LoxType::Number(ref s),&LoxType::Number(ref o)) => Ok(s.partial_cmp(o)),
+            (s,o) => Err(operand_error(op,"two numbers or two strings",s,o))
+        }
+    }
+}
+
+// A compiled function, its body is a chunk of its own with its own constants.
+#[derive(Debug)]
+pub struct Function {
+    pub name: Option<String>, // None for `fun (..) {}` expressions
+    pub params: Vec<String>,
+    pub upvalue_count: usize,
+    pub file: Option<Rc<str>>, // the module it's from, None for the main program
+    pub bytecode: Bytecode
+}
+
+impl Function {
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+}
+
+// A variable captured by a closure. It points into the vm's stack while the
+// variable is in scope and holds the value itself once it's gone.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(LoxType)
+}
+
+// What a function expression evaluates to at runtime: the function plus the
+// variables it captured from the scopes around it.
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>
+}
+
+// Upvalues can refer back to the closure holding them, so don't follow them.
+impl fmt::Debug for Closure {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"Closure({}, {} upvalues)",self.function,self.upvalues.len())
+    }
+}
+
+// Methods are copied down from the superclass when the class is declared, so
+// looking one up never has to walk the inheritance chain.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String,Rc<Closure>>>
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn find_method(&self,name: &str) -> Option<Rc<Closure>> {
+        self.methods.borrow().get(name).cloned()
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<String,LoxType>>
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new())
+        }
+    }
+}
+
+// Fields can refer back to the instance, so only list their names.
+impl fmt::Debug for Instance {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self.fields.borrow();
+        let mut names: Vec<&String> = fields.keys().collect();
+        names.sort();
+        write!(f,"Instance({}, {:?})",self.class.name,names)
+    }
+}
+
+// A method looked up on an instance, remembers the instance to call it with.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: LoxType,
+    pub method: Rc<Closure>
+}
+
+// What `import "file" as name` binds name to. Exports are looked up in the
+// module's globals when they're used, so they see later assignments.
+#[derive(Debug)]
+pub struct Module {
+    pub file: Rc<str>,
+    pub exports: Vec<String>
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref n) => write!(f,"<fn {}>",n),
+            None => write!(f,"<fn>")
         }
     }
 }
@@ -29,95 +159,89 @@ impl LoxType {
 impl PartialEq for LoxType {
     fn eq(&self,other: &LoxType) -> bool {
         match (self,other) {
-            (&LoxType::String(ref s),&LoxType::String(ref o)) => (s == o),
-            (&LoxType::Number(ref s),&LoxType::Number(ref o)) => (s == o),
+            (&LoxType::String(ref s),&LoxType::String(ref o)) => s == o,
+            (&LoxType::Number(ref s),&LoxType::Number(ref o)) => s == o,
             (&LoxType::Nil,&LoxType::Nil) => true,
-            (&LoxType::Boolean(ref s),&LoxType::Boolean(ref o)) => (s == o),
+            (&LoxType::Boolean(ref s),&LoxType::Boolean(ref o)) => s == o,
+            (&LoxType::Function(ref s),&LoxType::Function(ref o)) => Rc::ptr_eq(s,o),
+            (&LoxType::Closure(ref s),&LoxType::Closure(ref o)) => Rc::ptr_eq(s,o),
+            (&LoxType::Class(ref s),&LoxType::Class(ref o)) => Rc::ptr_eq(s,o),
+            (&LoxType::Instance(ref s),&LoxType::Instance(ref o)) => Rc::ptr_eq(s,o),
+            (&LoxType::BoundMethod(ref s),&LoxType::BoundMethod(ref o)) => Rc::ptr_eq(s,o),
+            (&LoxType::Module(ref s),&LoxType::Module(ref o)) => s.file == o.file,
             _ => false
         }
     }
 }
 
-impl PartialOrd for LoxType {
-    #[inline(never)]
-    fn partial_cmp(&self,other: &LoxType) -> Option<Ordering> {
-        match (self,other) {
-            (&LoxType::String(ref s),&LoxType::String(ref o)) => (s.partial_cmp(o)),
-            (&LoxType::Number(ref s),&LoxType::Number(ref o)) => (s.partial_cmp(o)),
-            (&LoxType::Nil,&LoxType::Nil) => Some(Ordering::Equal),
-            (&LoxType::Boolean(ref s),&LoxType::Boolean(ref o)) => (s.partial_cmp(o)),
-            _ => None
-        }
-    }
+// Arithmetic only works on numbers, and + on strings too. Anything else is an
+// error naming the types it was tried on.
+fn operand_error(op: &str,expected: &str,a: &LoxType,b: &LoxType) -> TypeError {
+    TypeError { msg: format!("Operands of '{}' must be {}, not {} and {}",op,expected,a.type_name(),b.type_name()) }
 }
 
 impl Add for LoxType {
-    type Output = Self;
+    type Output = Result<LoxType,TypeError>;
 
-    fn add(self,other: Self) -> Self {
+    fn add(self,other: Self) -> Self::Output {
         match (self,other) {
-            (LoxType::String(s),LoxType::String(o)) => LoxType::String(s + &o),
-            (LoxType::Number(s),LoxType::Number(o)) => LoxType::Number(s + o),
-            (LoxType::Nil,LoxType::Nil) => LoxType::Nil,
-            (LoxType::Boolean(s),LoxType::Boolean(o)) => LoxType::Boolean(s || o),
-            _ => LoxType::Nil
+            (LoxType::String(s),LoxType::String(o)) => Ok(LoxType::String(s + &o)),
+            (LoxType::Number(s),LoxType::Number(o)) => Ok(LoxType::Number(s + o)),
+            (s,o) => Err(operand_error("+","two numbers or two strings",&s,&o))
         }
     }
 }
 
 impl Sub for LoxType {
-    type Output = Self;
+    type Output = Result<LoxType,TypeError>;
 
-    fn sub(self,other: Self) -> Self {
+    fn sub(self,other: Self) -> Self::Output {
         match (self,other) {
-            (LoxType::String(s),LoxType::String(o)) => LoxType::Nil,
-            (LoxType::Number(s),LoxType::Number(o)) => LoxType::Number(s - o),
-            (LoxType::Nil,LoxType::Nil) => LoxType::Nil,
-            (LoxType::Boolean(s),LoxType::Boolean(o)) => LoxType::Nil,
-            _ => LoxType::Nil
+            (LoxType::Number(s),LoxType::Number(o)) => Ok(LoxType::Number(s - o)),
+            (s,o) => Err(operand_error("-","numbers",&s,&o))
         }
     }
 }
 
 impl Mul for LoxType {
-    type Output = Self;
+    type Output = Result<LoxType,TypeError>;
 
-    fn mul(self,other: Self) -> Self {
+    fn mul(self,other: Self) -> Self::Output {
         match (self,other) {
-            (LoxType::String(s),LoxType::String(o)) => LoxType::Nil,
-            (LoxType::Number(s),LoxType::Number(o)) => LoxType::Number(s * o),
-            (LoxType::Nil,LoxType::Nil) => LoxType::Nil,
-            (LoxType::Boolean(s),LoxType::Boolean(o)) => LoxType::Boolean(s && o),
-            _ => LoxType::Nil
+            (LoxType::Number(s),LoxType::Number(o)) => Ok(LoxType::Number(s * o)),
+            (s,o) => Err(operand_error("*","numbers",&s,&o))
         }
     }
 }
 
 impl Div for LoxType {
-    type Output = Self;
+    type Output = Result<LoxType,TypeError>;
 
-    fn div(self,other: Self) -> Self {
+    fn div(self,other: Self) -> Self::Output {
         match (self,other) {
-            (LoxType::String(s),LoxType::String(o)) => LoxType::Nil,
-            (LoxType::Number(s),LoxType::Number(o)) => LoxType::Number(s / o),
-            (LoxType::Nil,LoxType::Nil) => LoxType::Nil,
-            (LoxType::Boolean(s),LoxType::Boolean(o)) => LoxType::Nil,
-            _ => LoxType::Nil
+            (LoxType::Number(s),LoxType::Number(o)) => Ok(LoxType::Number(s / o)),
+            (s,o) => Err(operand_error("/","numbers",&s,&o))
+        }
+    }
+}
+
+impl Neg for LoxType {
+    type Output = Result<LoxType,TypeError>;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            LoxType::Number(n) => Ok(LoxType::Number(-n)),
+            v => Err(TypeError { msg: format!("Operand of '-' must be a number, not {}",v.type_name()) })
         }
     }
 }
 
+// Whether the value is falsey, nil and false are, everything else isn't.
 impl Not for LoxType {
     type Output = Self;
 
     fn not (self) -> Self {
-        match self {
-            LoxType::String(s)=> LoxType::Nil,
-            LoxType::Number(s) => LoxType::Number(-s),
-            LoxType::Nil => LoxType::Nil,
-            LoxType::Boolean(s) => LoxType::Boolean(!s),
-            _ => LoxType::Nil
-        }
+        LoxType::Boolean(!bool::from(self))
     }
 }
 
@@ -128,7 +252,12 @@ impl fmt::Display for LoxType {
             &LoxType::Number(ref n) => write!(f,"{}",n),
             &LoxType::Boolean(ref b) => write!(f,"{}",b),
             &LoxType::Nil => write!(f,"nil"),
-            _ => Ok(())
+            &LoxType::Function(ref func) => write!(f,"{}",func),
+            &LoxType::Closure(ref c) => write!(f,"{}",c.function),
+            &LoxType::Class(ref c) => write!(f,"{}",c.name),
+            &LoxType::Instance(ref i) => write!(f,"{} instance",i.class.name),
+            &LoxType::BoundMethod(ref b) => write!(f,"{}",b.method.function),
+            &LoxType::Module(ref m) => write!(f,"<module {}>",m.file)
         }
     }
 }
@@ -139,7 +268,7 @@ impl TryFrom<LoxType> for f64 {
         if let LoxType::Number(n) = value {
             Ok(n)
         } else {
-            Err(LoxError::new("Failed to cast LoxType into f64".to_string(),0))
+            Err(LoxError::new("Failed to cast LoxType into f64".to_string(),Span::default()))
         }
     }
 }
@@ -150,7 +279,7 @@ impl TryFrom<LoxType> for String {
         if let LoxType::String(s) = value {
             Ok(s)
         } else {
-            Err(LoxError::new("Failed to cast LoxType into String".to_string(),0))
+            Err(LoxError::new("Failed to cast LoxType into String".to_string(),Span::default()))
         }
     }
 }
@@ -161,7 +290,7 @@ impl TryFrom<LoxType> for bool {
         if let LoxType::Boolean(b) = value {
             Ok(b)
         } else {
-            Err(LoxError::new("Failed to cast LoxType into bool".to_string(),0))
+            Err(LoxError::new("Failed to cast LoxType into bool".to_string(),Span::default()))
         }
     }
 }***/
@@ -182,7 +311,7 @@ impl TryFrom<LoxType> for () {
         if let LoxType::Nil = value {
             Ok(())
         } else {
-            Err(LoxError::new("Failed to cast LoxType into ()".to_string(),0))
+            Err(LoxError::new("Failed to cast LoxType into ()".to_string(),Span::default()))
         }
     }
 }
@@ -1,39 +1,232 @@
-#[macro_use]
-extern crate lazy_static;
-
-mod lox_type;
-pub mod syntax;
-mod err;
-mod bytecode;
-
-use syntax::*;
-use lox_type::*;
-use err::*;
-use syntax::scanner::*;
-use syntax::parser::*;
-use syntax::statements::*;
-use syntax::statements::print_statements;
-use bytecode::compiler::*;
-use bytecode::vm::*;
-use std::panic;
-
-use std::rc::Rc;
-use std::time::*;
+mod repl;
+
+use relax::err::*;
+use relax::syntax::scanner::*;
+use relax::syntax::parser::*;
+use relax::bytecode::compiler::*;
+use relax::bytecode::vm::*;
+use relax::bytecode::{loxc,disasm};
+use relax::diagnostic::*;
+use relax::module::Loader;
+use std::env;
+use std::fs;
+use std::process;
+
+// exit codes, from sysexits.h
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+const USAGE: &str = "usage:
+    relax [repl]                      start an interactive session
+    relax run <file>                  run a .lox script or a compiled .loxc file
+    relax -e <code>                   run the given code
+    relax compile <file> -o <out>     compile a .lox script to bytecode
+    relax disasm <file>               disassemble a compiled .loxc file";
+
+enum Command {
+    Repl,
+    Run(String),
+    Eval(String),
+    Compile(String,String),
+    Disasm(String)
+}
+
+fn parse_args(args: Vec<String>) -> Result<Command,String> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        None | Some("repl") => Ok(Command::Repl),
+        Some("run") => {
+            let file = args.next().ok_or("missing file to run")?;
+            Ok(Command::Run(file))
+        },
+        Some("-e") | Some("eval") => {
+            let code = args.next().ok_or("missing code to evaluate")?;
+            Ok(Command::Eval(code))
+        },
+        Some("compile") => {
+            let mut file = None;
+            let mut out = None;
+            while let Some(a) = args.next() {
+                if a == "-o" {
+                    out = Some(args.next().ok_or("missing output file after '-o'")?);
+                } else {
+                    file = Some(a);
+                }
+            }
+            let file = file.ok_or("missing file to compile")?;
+            let out = out.unwrap_or_else(|| format!("{}.loxc",file.trim_end_matches(".lox")));
+            Ok(Command::Compile(file,out))
+        },
+        Some("disasm") => {
+            let file = args.next().ok_or("missing file to disassemble")?;
+            Ok(Command::Disasm(file))
+        },
+        Some(file) if !file.starts_with('-') => Ok(Command::Run(file.to_string())),
+        Some(other) => Err(format!("unknown command '{}'",other))
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Could not read '{}': {}",path,e);
+            process::exit(EX_NOINPUT);
+        }
+    }
+}
+
+fn report(file: &str,source: &str,errors: Vec<LoxError>) -> ! {
+    let colour = use_colour();
+    for e in &errors {
+        eprint!("{}",Diagnostic::from(e).render(source,file,colour));
+    }
+    process::exit(EX_DATAERR);
+}
+
+// Problems in imported modules are rendered against their own source.
+fn render_compile(file: &str,source: &str,e: &CompileError,d: Diagnostic) {
+    let colour = use_colour();
+    match e.file {
+        Some(ref f) => eprint!("{}",d.render(&f.source,&f.name,colour)),
+        None => eprint!("{}",d.render(source,file,colour))
+    }
+}
+
+fn report_compile(file: &str,source: &str,errors: Vec<CompileError>) -> ! {
+    for e in &errors {
+        render_compile(file,source,e,Diagnostic::from(&e.error));
+    }
+    process::exit(EX_DATAERR);
+}
+
+fn compile(file: &str,source: &str,loader: Loader) -> Chunk {
+    let mut scanner = Scanner::new(source.to_string());
+    // the scanner skips what it can't make sense of, so parse anyway to report
+    // as many errors as possible
+    let mut errors = scanner.scan().err().unwrap_or_default();
+    let (statements,parse_errors) = Parser::new(scanner.tokens).parse();
+    errors.extend(parse_errors);
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| e.span.start);
+        report(file,source,errors);
+    }
+    match relax::bytecode::compiler::compile(statements,loader) {
+        Ok(mut chunk) => {
+            for w in &chunk.loader.take_warnings() {
+                render_compile(file,source,w,Diagnostic::warning_from(&w.error));
+            }
+            chunk
+        },
+        Err(errors) => report_compile(file,source,errors)
+    }
+}
+
+// Compiled files don't come with their source or their modules', so their
+// runtime errors can't show a snippet.
+fn execute(b: Bytecode,file: &str,source: Option<&str>,loader: Option<&Loader>) {
+    if let Err(e) = interpret(&b) {
+        let d = Diagnostic::from(&e);
+        let module = e.file.as_ref().map(|f| (f.as_str(),loader.and_then(|l| l.source(f))));
+        match (module,source) {
+            (Some((f,Some(s))),_) => eprint!("{}",d.render(&s.source,f,use_colour())),
+            (Some((f,None)),_) => eprint!("{}",d.render_without_source(f,use_colour())),
+            (None,Some(source)) => eprint!("{}",d.render(source,file,use_colour())),
+            (None,None) => eprint!("{}",d.render_without_source(file,use_colour()))
+        }
+        process::exit(EX_SOFTWARE);
+    }
+}
+
+fn run_file(path: &str) {
+    let bytes = read_file(path);
+    if loxc::is_loxc(&bytes) {
+        match loxc::read(&mut bytes.as_slice()) {
+            Ok(b) => execute(b,path,None,None),
+            Err(e) => {
+                eprintln!("Invalid bytecode file '{}': {}",path,e);
+                process::exit(EX_DATAERR);
+            }
+        }
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => {
+                let chunk = compile(path,&source,Loader::for_file(Some(path)));
+                execute(chunk.to_bytecode(),path,Some(&source),Some(&chunk.loader));
+            },
+            Err(_) => {
+                eprintln!("'{}' is not valid utf-8",path);
+                process::exit(EX_DATAERR);
+            }
+        }
+    }
+}
+
+fn compile_file(path: &str,out: &str) {
+    let source = match String::from_utf8(read_file(path)) {
+        Ok(s) => s,
+        Err(_) => {
+            eprintln!("'{}' is not valid utf-8",path);
+            process::exit(EX_DATAERR);
+        }
+    };
+    let b = compile(path,&source,Loader::for_file(Some(path))).to_bytecode();
+    let res = fs::File::create(out).and_then(|mut f| loxc::write(&mut f,&b));
+    if let Err(e) = res {
+        eprintln!("Could not write '{}': {}",out,e);
+        process::exit(EX_IOERR);
+    }
+}
+
+fn disasm_file(path: &str) {
+    let bytes = read_file(path);
+    let b = match loxc::read(&mut bytes.as_slice()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid bytecode file '{}': {}",path,e);
+            process::exit(EX_DATAERR);
+        }
+    };
+
+    println!("== constants ==");
+    for (i,c) in b.constants.iter().enumerate() {
+        println!("{:04}  {}",i,c);
+    }
+    println!("== code ==");
+    match disasm::disassemble(&b) {
+        Ok(s) => print!("{}",s),
+        Err(e) => {
+            eprintln!("Malformed bytecode: {}",e);
+            process::exit(EX_DATAERR);
+        }
+    }
+}
 
 fn main() {
-    let og = "var n = 0; while (n < 10) { n = n + 1; } var b = 0;".to_string();
-    println!("Original Expression: {}", og);
-    let mut scanner = Scanner::new(og);
-    scanner.scan();
-    //println!("Tokens: {:?} \n", scanner.tokens);
-    let mut parser = Parser::new(scanner.tokens);
-    let statements = parser.parse().unwrap();
-    //print!("AST: {:?}\n",statements);
-    let mut compiler = Chunk::new();
-    compiler.compile_to_ops(statements);
-    println!("Operations: {:?}", compiler.ops);
-  //  println!("{:?}",compiler.encode_ops());
-    let time = SystemTime::now();
-    panic::catch_unwind(|| { interpret(compiler.encode_ops(),compiler.constants); });
-    println!("Ran for: {}",time.elapsed().unwrap().as_millis());
+    let command = match parse_args(env::args().skip(1).collect()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}\n{}",e,USAGE);
+            process::exit(EX_USAGE);
+        }
+    };
+
+    match command {
+        Command::Repl => {
+            if let Err(e) = repl::run() {
+                eprintln!("{}",e);
+                process::exit(EX_IOERR);
+            }
+        },
+        Command::Run(file) => run_file(&file),
+        Command::Eval(code) => {
+            let chunk = compile("<eval>",&code,Loader::for_file(None));
+            execute(chunk.to_bytecode(),"<eval>",Some(&code),Some(&chunk.loader));
+        },
+        Command::Compile(file,out) => compile_file(&file,&out),
+        Command::Disasm(file) => disasm_file(&file)
+    }
 }
@@ -0,0 +1,178 @@
+use super::err::{LoxError,CompileError};
+use super::lox_type::Function;
+use super::syntax::scanner::Scanner;
+use super::syntax::parser::Parser;
+use super::syntax::statements::Statement;
+use super::syntax::token::Span;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::rc::Rc;
+
+// Directories searched for imports that aren't found next to the importing
+// file, separated like PATH.
+pub const SEARCH_PATH_VAR: &str = "RELAX_PATH";
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String
+}
+
+// Finds, reads and parses the files named by import statements. Each module is
+// compiled once, the compiler hands the result back to be cached.
+#[derive(Debug,Default)]
+pub struct Loader {
+    root: PathBuf, // where imports of the main program are resolved from
+    main: Option<(PathBuf,String)>, // the main program's file, if it has one
+    search_path: Vec<PathBuf>,
+    compiled: HashMap<PathBuf,(Rc<Function>,Vec<String>)>, // with the names it exports
+    loading: Vec<(PathBuf,Rc<SourceFile>)>, // the chain of imports being compiled
+    sources: HashMap<String,Rc<SourceFile>>, // of the compiled modules, by file name
+    errors: Vec<CompileError>,
+    warnings: Vec<CompileError> // problems that don't stop the program from compiling
+}
+
+pub enum Import {
+    Compiled(Rc<Function>,Vec<String>),
+    Parsed(PathBuf,Vec<Rc<Statement>>),
+    Failed
+}
+
+impl Loader {
+    pub fn new(root: PathBuf,search_path: Vec<PathBuf>) -> Loader {
+        Loader {
+            root,
+            search_path,
+            ..Loader::default()
+        }
+    }
+
+    // Resolves imports relative to `file`, or the working directory if there's
+    // no file, with the search path taken from the environment.
+    pub fn for_file(file: Option<&str>) -> Loader {
+        let root = file.and_then(|f| Path::new(f).parent()).map(Path::to_path_buf).unwrap_or_default();
+        let search_path = match env::var_os(SEARCH_PATH_VAR) {
+            Some(p) => env::split_paths(&p).collect(),
+            None => Vec::new()
+        };
+        let mut loader = Loader::new(root,search_path);
+        loader.main = file.and_then(|f| Some((Path::new(f).canonicalize().ok()?,f.to_string())));
+        loader
+    }
+
+    pub fn take_errors(&mut self) -> Vec<CompileError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<CompileError> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors.len()
+    }
+
+    // The file of the module currently being compiled, None for the main program.
+    pub fn current_file(&self) -> Option<String> {
+        self.loading.last().map(|&(_,ref f)| f.name.clone())
+    }
+
+    // Errors found while compiling are recorded against the file being
+    // compiled too.
+    pub fn error(&mut self,msg: String,span: Span) {
+        self.add_error(LoxError::new(msg,span));
+    }
+
+    pub fn add_error(&mut self,error: LoxError) {
+        let file = self.loading.last().map(|&(_,ref f)| f.clone());
+        self.errors.push(CompileError { file, error });
+    }
+
+    pub fn add_warning(&mut self,warning: LoxError) {
+        let file = self.loading.last().map(|&(_,ref f)| f.clone());
+        self.warnings.push(CompileError { file, error: warning });
+    }
+
+    fn resolve(&self,name: &str) -> Option<PathBuf> {
+        let dir = match self.loading.last() {
+            Some(&(ref path,_)) => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => self.root.clone()
+        };
+        std::iter::once(&dir).chain(self.search_path.iter())
+            .map(|d| d.join(name))
+            .find(|p| p.is_file())
+            .and_then(|p| p.canonicalize().ok())
+    }
+
+    // Either the module's already compiled, or it's parsed and the caller has
+    // to compile it and hand it to `finish`. Problems are recorded as errors.
+    pub fn import(&mut self,name: &str,span: Span) -> Import {
+        let path = match self.resolve(name) {
+            Some(p) => p,
+            None => {
+                self.error(format!("Couldn't find module '{}'",name),span);
+                return Import::Failed;
+            }
+        };
+        if let Some(&(ref f,ref exports)) = self.compiled.get(&path) {
+            return Import::Compiled(f.clone(),exports.clone());
+        }
+        let mut chain: Vec<&str> = self.loading.iter().map(|&(_,ref f)| f.name.as_str()).collect();
+        let start = match self.main {
+            Some((ref p,ref f)) if *p == path => {
+                chain.insert(0,f);
+                Some(0)
+            },
+            _ => self.loading.iter().position(|&(ref p,_)| *p == path)
+        };
+        if let Some(i) = start {
+            let msg = format!("Import cycle: {} -> {}",chain[i..].join(" -> "),name);
+            self.error(msg,span);
+            return Import::Failed;
+        }
+
+        let source = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) => {
+                let file = self.loading.last().map(|&(_,ref f)| f.clone());
+                self.errors.push(CompileError { file, error: LoxError::with_lower(format!("Couldn't read module '{}'",name),span,e) });
+                return Import::Failed;
+            }
+        };
+        let file = Rc::new(SourceFile { name: path.display().to_string(), source });
+
+        let mut scanner = Scanner::new(file.source.clone());
+        let mut errors = scanner.scan().err().unwrap_or_default();
+        let (statements,parse_errors) = Parser::new(scanner.tokens).parse();
+        errors.extend(parse_errors);
+        if !errors.is_empty() {
+            errors.sort_by_key(|e| e.span.start);
+            self.errors.extend(errors.into_iter().map(|error| CompileError { file: Some(file.clone()), error }));
+            return Import::Failed;
+        }
+
+        self.loading.push((path.clone(),file));
+        Import::Parsed(path,statements)
+    }
+
+    pub fn finish(&mut self,path: PathBuf,f: Rc<Function>,exports: Vec<String>) {
+        if let Some((_,file)) = self.loading.pop() {
+            self.sources.insert(file.name.clone(),file);
+        }
+        self.compiled.insert(path,(f,exports));
+    }
+
+    // The source a compiled module was read from, runtime errors in it are
+    // rendered against what was compiled rather than what's on disk now.
+    pub fn source(&self,file: &str) -> Option<&SourceFile> {
+        self.sources.get(file).map(|f| &**f)
+    }
+
+    // Gives up on the module being compiled after errors in it, it isn't
+    // cached so importing it again reports them again.
+    pub fn abandon(&mut self) {
+        self.loading.pop();
+    }
+}
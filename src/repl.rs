@@ -0,0 +1,115 @@
+use relax::err::{LoxError,CompileError};
+use relax::syntax::scanner::*;
+use relax::syntax::parser::*;
+use relax::syntax::statements::*;
+use relax::bytecode::compiler::*;
+use relax::bytecode::vm::*;
+use relax::diagnostic::*;
+use relax::module::Loader;
+use std::io::{self,BufRead,Write};
+use std::rc::Rc;
+
+enum Input {
+    Ready(Vec<Rc<Statement>>),
+    Incomplete,
+    Failed(Vec<LoxError>)
+}
+
+fn read_input(source: &str) -> Input {
+    let mut scanner = Scanner::new(source.to_string());
+    let mut errors = scanner.scan().err().unwrap_or_default();
+    if scanner.is_incomplete() {
+        return Input::Incomplete;
+    }
+    let (statements,parse_errors) = Parser::new(scanner.tokens).parse();
+    errors.extend(parse_errors);
+    if errors.is_empty() {
+        Input::Ready(statements)
+    } else {
+        errors.sort_by_key(|e| e.span.start);
+        Input::Failed(errors)
+    }
+}
+
+fn prompt(s: &str) -> io::Result<()> {
+    let mut out = io::stdout();
+    out.write_all(s.as_bytes())?;
+    out.flush()
+}
+
+// Problems in imported modules are rendered against their own source.
+fn render_compile(buffer: &str,e: &CompileError,d: Diagnostic) {
+    match e.file {
+        Some(ref f) => eprint!("{}",d.render(&f.source,&f.name,use_colour())),
+        None => eprint!("{}",d.render(buffer,"<repl>",use_colour()))
+    }
+}
+
+// Every input is compiled into the same chunk and loaded into the same vm,
+// which picks up from where the previous input ended, so globals stay alive
+// between lines.
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut chunk = Chunk::with_loader(Loader::for_file(None));
+    let mut vm = Vm::new();
+    let mut buffer = String::new();
+
+    loop {
+        prompt(if buffer.is_empty() { "> " } else { "... " })?;
+        let line = match lines.next() {
+            Some(l) => l?,
+            None => break
+        };
+        buffer.push_str(&line);
+        buffer.push('\n');
+
+        let statements = match read_input(&buffer) {
+            Input::Ready(s) => s,
+            Input::Incomplete => continue,
+            Input::Failed(errors) => {
+                for e in &errors {
+                    eprint!("{}",Diagnostic::from(e).render(&buffer,"<repl>",use_colour()));
+                }
+                buffer.clear();
+                continue;
+            }
+        };
+
+        let echo = matches!(statements.last().map(|s| &**s),Some(&Statement::Expression(_)));
+        let start = chunk.ops.len();
+        let compiled = chunk.compile_to_ops(statements);
+        for w in &chunk.loader.take_warnings() {
+            render_compile(&buffer,w,Diagnostic::warning_from(&w.error));
+        }
+        if let Err(errors) = compiled {
+            for e in &errors {
+                render_compile(&buffer,e,Diagnostic::from(&e.error));
+            }
+            buffer.clear();
+            continue;
+        }
+        vm.load(&chunk.to_bytecode_from(start));
+
+        match vm.interpret() {
+            Ok(v) => if echo { println!("{}",v) },
+            Err(e) => {
+                let d = Diagnostic::from(&e);
+                match e.file {
+                    Some(ref f) => match chunk.loader.source(f) {
+                        Some(s) => eprint!("{}",d.render(&s.source,f,use_colour())),
+                        None => eprint!("{}",d.render_without_source(f,use_colour()))
+                    },
+                    // only the top level code is sure to be from this input,
+                    // functions may have been entered earlier
+                    None if e.trace.is_empty() => eprint!("{}",d.render(&buffer,"<repl>",use_colour())),
+                    None => eprint!("{}",d.render_without_source("<repl>",use_colour()))
+                }
+                vm.skip_to_end();
+            }
+        }
+        buffer.clear();
+    }
+    println!();
+    Ok(())
+}
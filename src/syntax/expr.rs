@@ -12,7 +12,25 @@ pub enum Expr {
     Assign(Token,Rc<Expr>),
     Logical(Rc<Expr>,Token,Rc<Expr>),
     Call(Rc<Expr>,Token,Vec<Rc<Expr>>),
-    Function(Vec<Token>,Rc<Statement>)
+    Function(Token,Vec<Token>,Rc<Statement>), // `fun`, parameters, body
+    Get(Rc<Expr>,Token),
+    Set(Rc<Expr>,Token,Rc<Expr>),
+    This(Token),
+    Super(Token,Token)
+}
+
+impl Expr {
+    // The leftmost token of the expression, if it isn't a literal.
+    pub fn first_token(&self) -> Option<&Token> {
+        match self {
+            &Expr::Binary(ref e,ref t,_) | &Expr::Logical(ref e,ref t,_) | &Expr::Call(ref e,ref t,_)
+                | &Expr::Get(ref e,ref t) | &Expr::Set(ref e,ref t,_) => e.first_token().or(Some(t)),
+            &Expr::Grouping(ref e) => e.first_token(),
+            &Expr::Literal(_) => None,
+            &Expr::Unary(ref t,_) | &Expr::Variable(ref t) | &Expr::Assign(ref t,_) | &Expr::Function(ref t,_,_)
+                | &Expr::This(ref t) | &Expr::Super(ref t,_) => Some(t)
+        }
+    }
 }
 
 impl fmt::Display for Expr {
@@ -46,14 +64,26 @@ impl fmt::Display for Expr {
                 }
                 write!(f,")")
             },
-            &Expr::Function(ref t, ref s) => {
+            &Expr::Function(_,ref t, ref s) => {
                 write!(f,"fn (")?;
                 for token in t {
                     write!(f,"{}",token.lexeme)?;
                 }
                 write!(f,") ")?;
                 write!(f,"{}",*s)
-            } // IMPL pretty printing for functions
+            }, // IMPL pretty printing for functions
+            &Expr::Get(ref object,ref name) => {
+                write!(f,"{}.{}",object,&name.lexeme)
+            },
+            &Expr::Set(ref object,ref name,ref e) => {
+                write!(f,"{}.{} = {}",object,&name.lexeme,e)
+            },
+            &Expr::This(_) => {
+                write!(f,"this")
+            },
+            &Expr::Super(_,ref method) => {
+                write!(f,"super.{}",&method.lexeme)
+            }
         }
     }
 }
@@ -4,13 +4,11 @@ pub mod statements;
 pub mod token_type;
 pub mod token;
 pub mod expr;
+pub mod resolver;
 
-use parser::*;
-use scanner::*;
 use statements::*;
 use token_type::*;
 use token::*;
 use expr::*;
-use statements::*;
 use super::lox_type::*;
 use super::err::*;
@@ -1,6 +1,9 @@
 use super::*;
 use std::rc::Rc;
 
+// Arguments and parameters are counted with a single byte operand.
+pub const MAX_ARGS: usize = 255;
+
 pub enum FunKind {
     Function,
     Method
@@ -21,39 +24,54 @@ macro_rules! try_sync {
 pub struct Parser {
     current: usize,
     tokens: Vec<Token>,
-    current_depth: usize
+    current_depth: usize,
+    errors: Vec<LoxError>
 }
 
 type ParseResult = Result<Expr,LoxError>;
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser{
-        Parser { current: 0, tokens: tokens, current_depth: 0 }
+        Parser { current: 0, tokens, current_depth: 0, errors: Vec::new() }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Rc<Statement>>,LoxError> {
+    // Keeps going after errors, so everything that's wrong gets reported in one
+    // go. Statements that failed to parse are left out of the returned AST.
+    pub fn parse(&mut self) -> (Vec<Rc<Statement>>,Vec<LoxError>) {
 //        println!("{:?}",&self.tokens);
         let mut statements: Vec<Rc<Statement>> = Vec::new();
 
         while !self.is_end() {
-            statements.push(Rc::new(self.declaration()?));
+            match self.declaration() {
+                Ok(s) => statements.push(Rc::new(s)),
+                Err(e) => self.errors.push(e)
+            }
         }
-        Ok(statements)
+        (statements,std::mem::take(&mut self.errors))
     }
 
     fn sync(&mut self) {
-        self.advance();
+        // leave a block's closing brace for the block to consume, otherwise an
+        // error on its last line swallows the rest of the file
+        if !(self.current_depth > 0 && self.check(TokenType::RightBrace)) {
+            self.advance();
+        }
         while !self.is_end() {
-            if self.previous().token == TokenType::Semicolon { return (); }
+            if self.previous().token == TokenType::Semicolon { return; }
             match self.peek().token {
-                TokenType::Class => return (),
-                TokenType::Fun => return (),
-                TokenType::Var => return (),
-                TokenType::For => return (),
-                TokenType::If => return (),
-                TokenType::While => return (),
-                TokenType::Import => return (),
-                TokenType::Return => return (),
+                TokenType::RightBrace if self.current_depth > 0 => return,
+                TokenType::Class => return,
+                TokenType::Fun => return,
+                TokenType::Var => return,
+                TokenType::For => return,
+                TokenType::If => return,
+                TokenType::While => return,
+                TokenType::Import => return,
+                TokenType::Export => return,
+                TokenType::Print => return,
+                TokenType::Return => return,
+                TokenType::Break => return,
+                TokenType::Continue => return,
                 _ => ()
             };
             self.advance();
@@ -75,7 +93,7 @@ impl Parser {
         if self.check(t) {
             Ok(self.advance())
         } else {
-            Err(LoxError::new(msg,self.peek().line))
+            Err(LoxError::new(msg,self.peek().span))
         }
     }
 
@@ -94,6 +112,13 @@ impl Parser {
         // This probably shouldn't clone, but im tired AND lazy.
     }
 
+    fn check_next(&mut self,t: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(next) => next.token == t,
+            None => false
+        }
+    }
+
     fn previous(&mut self) -> Token {
         self.tokens[self.current - 1].clone()
     }
@@ -105,13 +130,40 @@ impl Parser {
     // Grammar rules
 
     fn declaration(&mut self) -> Result<Statement,LoxError> {
-        if self.match_t(vec![TokenType::Var]) {
+        if self.match_t(vec![TokenType::Export]) {
+            Ok(try_sync!(self,self.export_declaration()))
+        } else if self.match_t(vec![TokenType::Var]) {
             Ok(try_sync!(self,self.var_statement()))
+        } else if self.match_t(vec![TokenType::Class]) {
+            Ok(try_sync!(self,self.class_declaration()))
+        } else if self.check(TokenType::Fun) && self.check_next(TokenType::Identifier) {
+            // `fun (..) {}` without a name is an expression, leave it to primary()
+            self.advance();
+            Ok(try_sync!(self,self.function(FunKind::Function)))
         } else {
             Ok(try_sync!(self,self.statement()))
         }
     }
 
+    // Only top-level declarations can be exported, the rest of a module is
+    // private to it.
+    fn export_declaration(&mut self) -> Result<Statement,LoxError> {
+        let keyword = self.previous();
+        if self.current_depth > 0 {
+            return Err(LoxError::new("Exports are only allowed at the top level".to_string(),keyword.span));
+        }
+        let declaration = if self.match_t(vec![TokenType::Var]) {
+            self.var_statement()?
+        } else if self.match_t(vec![TokenType::Class]) {
+            self.class_declaration()?
+        } else if self.match_t(vec![TokenType::Fun]) {
+            self.function(FunKind::Function)?
+        } else {
+            return Err(LoxError::new("Expected 'var', 'fun' or 'class' after 'export'".to_string(),self.peek().span));
+        };
+        Ok(Statement::Export(keyword,Rc::new(declaration)))
+    }
+
     fn var_statement(&mut self) -> Result<Statement,LoxError> {
         let name = self.consume(TokenType::Identifier,"Expected variable name".to_string())?;
         let mut initializer: Option<Expr> = None;
@@ -132,47 +184,114 @@ impl Parser {
             self.while_statement()
         } else if self.match_t(vec![TokenType::For]) {
             self.for_statement()
+        } else if self.match_t(vec![TokenType::Print]) {
+            self.print_statement()
         } else if self.match_t(vec![TokenType::Return]) {
             self.return_statement()
         } else if self.match_t(vec![TokenType::Import]) {
             self.import_statement()
+        } else if self.match_t(vec![TokenType::Break,TokenType::Continue]) {
+            self.loop_jump_statement()
         } else {
             self.expr_statement()
         }
     }
 
     fn import_statement(&mut self) -> Result<Statement,LoxError> {
+        let keyword = self.previous();
+        // a module's declarations become globals, so they can't go in a block
+        if self.current_depth > 0 {
+            return Err(LoxError::new("Imports are only allowed at the top level".to_string(),keyword.span));
+        }
         let file = self.consume(TokenType::String,"Expected 'string' after 'import'".to_string())?;
+        // `as` is only special here, it's fine as a name everywhere else
+        let alias = if self.check(TokenType::Identifier) && self.peek().lexeme == "as" {
+            self.advance();
+            Some(self.consume(TokenType::Identifier,"Expected module name after 'as'".to_string())?)
+        } else {
+            None
+        };
         self.consume(TokenType::Semicolon,"Expected ';' after import statement".to_string())?;
-        Ok(Statement::Import(file))
+        Ok(Statement::Import(file,alias))
+    }
+    fn class_declaration(&mut self) -> Result<Statement,LoxError> {
+        let name = self.consume(TokenType::Identifier,"Expected class name".to_string())?;
+        let superclass = if self.match_t(vec![TokenType::Less]) {
+            let s = self.consume(TokenType::Identifier,"Expected superclass name".to_string())?;
+            if s.lexeme == name.lexeme {
+                return Err(LoxError::new("A class can't inherit from itself".to_string(),s.span));
+            }
+            Some(Expr::Variable(s))
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace,"Expected '{' before class body".to_string())?;
+        let mut methods: Vec<Rc<Statement>> = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_end() {
+            methods.push(Rc::new(self.function(FunKind::Method)?));
+        }
+        self.consume(TokenType::RightBrace,"Expected '}' after class body".to_string())?;
+        Ok(Statement::Class(name,superclass,methods))
     }
-/*
+
     fn function(&mut self, kind: FunKind) -> Result<Statement,LoxError> {
-        let name = self.consume(TokenType::Identifier, "Expected function/method name.".to_string())?;
+        let msg = match kind {
+            FunKind::Function => "Expected function name",
+            FunKind::Method => "Expected method name"
+        };
+        let name = self.consume(TokenType::Identifier,msg.to_string())?;
         self.consume(TokenType::LeftParenthesis,"Expected '(' after fun name declaration".to_string())?;
 
+        let (parameters,body) = self.function_body()?;
+        Ok(Statement::Function(name,parameters,Rc::new(body)))
+    }
+
+    fn function_body(&mut self) -> Result<(Vec<Token>,Statement),LoxError> {
         let mut parameters: Vec<Token> = Vec::new();
         if !self.check(TokenType::RightParenthesis) {
-            parameters.push(self.consume(TokenType::Identifier,"Expected parameter name".to_string())?);
-            while self.match_t(vec![TokenType::Comma]) {
+            loop {
+                if parameters.len() >= MAX_ARGS {
+                    return Err(LoxError::new(format!("Can't have more than {} parameters",MAX_ARGS),self.peek().span));
+                }
                 parameters.push(self.consume(TokenType::Identifier,"Expected parameter name".to_string())?);
+                if !self.match_t(vec![TokenType::Comma]) { break; }
             }
         }
 
         self.consume(TokenType::RightParenthesis,"Expected ')' after parameters.".to_string())?;
         self.consume(TokenType::LeftBrace,"Expected '{' before function/method body".to_string())?;
         let body = self.block_statement()?;
-        Ok(Statement::Function(name,parameters,Rc::new(body)))
+        Ok((parameters,body))
+    }
+
+    fn print_statement(&mut self) -> Result<Statement,LoxError> {
+        let t = self.previous();
+        let v = self.expression()?;
+        self.consume(TokenType::Semicolon,"Expected ';' after value".to_string())?;
+        Ok(Statement::Print(t,v))
     }
-*/
+
     fn return_statement(&mut self) -> Result<Statement,LoxError> {
         let t = self.previous();
-        let v = if !self.check(TokenType::Semicolon) { self.expression()? } else { Expr::Literal(LoxType::Nil) };
+        let v = if !self.check(TokenType::Semicolon) { Some(self.expression()?) } else { None };
         self.consume(TokenType::Semicolon,"Expected ';' after return value".to_string())?;
         Ok(Statement::Return(t,v))
     }
 
+    // Whether they're inside a loop is up to the resolver.
+    fn loop_jump_statement(&mut self) -> Result<Statement,LoxError> {
+        let t = self.previous();
+        self.consume(TokenType::Semicolon,format!("Expected ';' after '{}'",t.lexeme))?;
+        if t.token == TokenType::Break {
+            Ok(Statement::Break(t))
+        } else {
+            Ok(Statement::Continue(t))
+        }
+    }
+
     fn for_statement(&mut self) -> Result<Statement,LoxError> {
+        let keyword = self.previous();
         self.consume(TokenType::LeftParenthesis, "Expected '(' after 'for'".to_string())?;
 
         let initializer = if self.match_t(vec![TokenType::Semicolon]) {
@@ -191,31 +310,28 @@ impl Parser {
 
         self.consume(TokenType::RightParenthesis, "Expected ')' after for clauses".to_string())?;
 
-        let mut body = self.statement()?;
-
-        if let Some(i) = increment {
-            body = Statement::Block(vec![Rc::new(body),Rc::new(Statement::Expression(i))]);
-        }
-
-        body = Statement::While(cond,Rc::new(body));
+        // the increment stays apart from the body, continue runs it too
+        let mut body = Statement::While(keyword.clone(),cond,Rc::new(self.statement()?),increment);
 
         if let Some(init) = initializer {
-            body = Statement::Block(vec![Rc::new(init),Rc::new(body)]);
+            body = Statement::Block(keyword,vec![Rc::new(init),Rc::new(body)]);
         }
 
         Ok(body)
     }
 
     fn while_statement(&mut self) -> Result<Statement,LoxError> {
+        let keyword = self.previous();
         self.consume(TokenType::LeftParenthesis,"Expected '(' after while".to_string())?;
         let e = self.expression()?;
         self.consume(TokenType::RightParenthesis,"Expected ')' after while".to_string())?;
         let body = self.statement()?;
 
-        Ok(Statement::While(e,Rc::new(body)))
+        Ok(Statement::While(keyword,e,Rc::new(body),None))
     }
 
     fn if_statement(&mut self) -> Result<Statement,LoxError> {
+        let keyword = self.previous();
         self.consume(TokenType::LeftParenthesis,"Expected '(' after 'if'".to_string())?;
         let cond = self.expression()?;
         self.consume(TokenType::RightParenthesis,"Expected ')' after 'if' condition".to_string())?;
@@ -226,19 +342,24 @@ impl Parser {
         } else {
             None
         };
-        Ok(Statement::If(cond,then,or))
+        Ok(Statement::If(keyword,cond,then,or))
     }
 
+    // After the `{`.
     fn block_statement(&mut self) -> Result<Statement,LoxError> {
+        let brace = self.previous();
         self.current_depth += 1;
         let mut statements: Vec<Rc<Statement>> = Vec::new();
         while !self.check(TokenType::RightBrace) && !self.is_end() {
-            statements.push(Rc::new(self.declaration()?));
+            match self.declaration() {
+                Ok(s) => statements.push(Rc::new(s)),
+                Err(e) => self.errors.push(e)
+            }
         }
 
-        self.consume(TokenType::RightBrace,"Expected '}' after block".to_string())?;
         self.current_depth -= 1;
-        Ok(Statement::Block(statements))
+        self.consume(TokenType::RightBrace,"Expected '}' after block".to_string())?;
+        Ok(Statement::Block(brace,statements))
     }
 
     fn expr_statement(&mut self) -> Result<Statement,LoxError> {
@@ -258,11 +379,11 @@ impl Parser {
             let equals = self.previous();
             let value = self.assignment()?;
 
-            if let Expr::Variable(name) = e {
-                return Ok(Expr::Assign(name,Rc::new(value)));
-            }
-
-            return Err(LoxError::new("Invalid assignment target".to_string(),equals.line));
+            return match e {
+                Expr::Variable(name) => Ok(Expr::Assign(name,Rc::new(value))),
+                Expr::Get(object,name) => Ok(Expr::Set(object,name,Rc::new(value))),
+                _ => Err(LoxError::new("Invalid assignment target".to_string(),equals.span))
+            };
         }
 
         Ok(e)
@@ -347,8 +468,15 @@ impl Parser {
 
     fn call(&mut self) -> ParseResult {
         let mut e = self.primary()?;
-        if self.match_t(vec![TokenType::LeftParenthesis]) {
-            e = self.finish_call(e)?;
+        loop {
+            if self.match_t(vec![TokenType::LeftParenthesis]) {
+                e = self.finish_call(e)?;
+            } else if self.match_t(vec![TokenType::Dot]) {
+                let name = self.consume(TokenType::Identifier,"Expected property name after '.'".to_string())?;
+                e = Expr::Get(Rc::new(e),name);
+            } else {
+                break;
+            }
         }
         Ok(e)
     }
@@ -356,9 +484,12 @@ impl Parser {
     fn finish_call(&mut self,expr: Expr) -> ParseResult {
         let mut arguments: Vec<Rc<Expr>> = Vec::new();
         if !self.check(TokenType::RightParenthesis) {
-            arguments.push(Rc::new(self.expression()?));
-            while self.match_t(vec![TokenType::Comma]) {
+            loop {
+                if arguments.len() >= MAX_ARGS {
+                    return Err(LoxError::new(format!("Can't have more than {} arguments",MAX_ARGS),self.peek().span));
+                }
                 arguments.push(Rc::new(self.expression()?));
+                if !self.match_t(vec![TokenType::Comma]) { break; }
             }
         }
 
@@ -380,6 +511,17 @@ impl Parser {
             return Ok(Expr::Variable(self.previous()))
         }
 
+        if self.match_t(vec![TokenType::This]) {
+            return Ok(Expr::This(self.previous()))
+        }
+
+        if self.match_t(vec![TokenType::Super]) {
+            let keyword = self.previous();
+            self.consume(TokenType::Dot,"Expected '.' after 'super'".to_string())?;
+            let method = self.consume(TokenType::Identifier,"Expected superclass method name".to_string())?;
+            return Ok(Expr::Super(keyword,method))
+        }
+
         if self.match_t(vec![TokenType::LeftParenthesis]) {
             let e = self.expression()?;
             self.consume(TokenType::RightParenthesis,"Expected ')' after expression".to_string())?;
@@ -387,21 +529,11 @@ impl Parser {
         };
 
         if self.match_t(vec![TokenType::Fun]) {
+            let keyword = self.previous();
             self.consume(TokenType::LeftParenthesis,"Expected '(' after fun declaration".to_string())?;
-
-            let mut parameters: Vec<Token> = Vec::new();
-            if !self.check(TokenType::RightParenthesis) {
-                parameters.push(self.consume(TokenType::Identifier,"Expected parameter name".to_string())?);
-                while self.match_t(vec![TokenType::Comma]) {
-                    parameters.push(self.consume(TokenType::Identifier,"Expected parameter name".to_string())?);
-                }
-            }
-
-            self.consume(TokenType::RightParenthesis,"Expected ')' after parameters.".to_string())?;
-            self.consume(TokenType::LeftBrace,"Expected '{' before function/method body".to_string())?;
-            let body = self.block_statement()?;
-            return Ok(Expr::Function(parameters,Rc::new(body)))
+            let (parameters,body) = self.function_body()?;
+            return Ok(Expr::Function(keyword,parameters,Rc::new(body)))
         }
-        Err(LoxError::new("Expected expression".to_string(),self.peek().line))
+        Err(LoxError::new("Expected expression".to_string(),self.peek().span))
     }
 }
@@ -0,0 +1,375 @@
+use super::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Where a name refers to, worked out before compiling so the compiler can
+// emit slot accesses instead of looking for variables by name.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+    Local(u16), // slot in the current call's locals
+    Upvalue(u8), // index into the closure's upvalues
+    Global // looked up by name when the code runs
+}
+
+// How a closure gets hold of one of its upvalues, see CLOSURE.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Capture {
+    pub is_local: bool,
+    pub index: u16 // a local's slot or an upvalue's index
+}
+
+// What the resolver found out, keyed by where the token it's about starts.
+#[derive(Debug,Default)]
+pub struct Resolution {
+    accesses: HashMap<usize,Access>,
+    captures: HashMap<usize,Vec<Capture>>
+}
+
+impl Resolution {
+    // Names are resolved by their token. `super.m` resolves `super` by the
+    // keyword and the `this` it binds to by the method's name.
+    pub fn access(&self,t: &Token) -> Access {
+        self.accesses.get(&t.span.start).copied().unwrap_or(Access::Global)
+    }
+
+    // What a function captures, by its name or, for function expressions,
+    // the `fun` keyword.
+    pub fn captures(&self,t: &Token) -> &[Capture] {
+        self.captures.get(&t.span.start).map(|c| c.as_slice()).unwrap_or(&[])
+    }
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    initialized: bool, // false while its initializer is being resolved
+    declared: Option<Span> // None for `this` and `super`
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum FunctionKind {
+    Script, // the top level of a program or module
+    Function,
+    Method,
+    Initializer // a class's init method, which always gives the instance
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum ClassKind {
+    Class,
+    Subclass
+}
+
+// The locals of a function being resolved, its slots are their indexes.
+struct Scope {
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    captures: Vec<Capture>,
+    depth: usize,
+    loops: usize // how many loops the code being resolved is in
+}
+
+impl Scope {
+    fn new(kind: FunctionKind) -> Scope {
+        Scope {
+            kind,
+            locals: Vec::new(),
+            captures: Vec::new(),
+            depth: 0,
+            loops: 0
+        }
+    }
+
+    fn resolve_local(&self,name: &str) -> Option<(u16,bool)> {
+        self.locals.iter().rposition(|l| l.name == name).map(|i| (i as u16,self.locals[i].initialized))
+    }
+}
+
+pub struct Resolver {
+    functions: Vec<Scope>,
+    classes: Vec<ClassKind>,
+    resolution: Resolution,
+    errors: Vec<LoxError>,
+    warnings: Vec<LoxError>
+}
+
+impl Default for Resolver {
+    fn default() -> Resolver {
+        Resolver {
+            functions: vec![Scope::new(FunctionKind::Script)],
+            classes: Vec::new(),
+            resolution: Resolution::default(),
+            errors: Vec::new(),
+            warnings: Vec::new()
+        }
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    // The warnings come back whether or not there are errors.
+    pub fn resolve(mut self,st: &[Rc<Statement>]) -> (Result<Resolution,Vec<LoxError>>,Vec<LoxError>) {
+        self.statements(st);
+        self.warnings.sort_by_key(|e| e.span.start);
+        if self.errors.is_empty() {
+            (Ok(self.resolution),self.warnings)
+        } else {
+            self.errors.sort_by_key(|e| e.span.start);
+            (Err(self.errors),self.warnings)
+        }
+    }
+
+    fn error(&mut self,msg: String,t: &Token) {
+        self.errors.push(LoxError::new(msg,t.span));
+    }
+
+    fn warning(&mut self,msg: String,t: &Token) {
+        self.warnings.push(LoxError::new(msg,t.span));
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.functions.last_mut().unwrap()
+    }
+
+    // Declarations at the top level are globals, which aren't tracked.
+    fn declare(&mut self,t: &Token) {
+        let scope = self.functions.last().unwrap();
+        if scope.kind == FunctionKind::Script && scope.depth == 0 {
+            return;
+        }
+        let depth = scope.depth;
+        let earlier = scope.locals.iter().rev().take_while(|l| l.depth == depth).find(|l| l.name == t.lexeme);
+        if let Some(earlier) = earlier {
+            let mut e = LoxError::new(format!("Already a variable named '{}' in this scope",t.lexeme),t.span);
+            if let Some(span) = earlier.declared {
+                e = e.with_label(span,"first declared here".to_string());
+            }
+            self.errors.push(e);
+        } else if scope.locals.len() > u16::MAX as usize {
+            self.error("Too many local variables in function".to_string(),t);
+        }
+        self.scope().locals.push(Local { name: t.lexeme.clone(), depth, initialized: false, declared: Some(t.span) });
+    }
+
+    fn define(&mut self) {
+        let scope = self.scope();
+        if scope.kind != FunctionKind::Script || scope.depth > 0 {
+            if let Some(l) = scope.locals.last_mut() {
+                l.initialized = true;
+            }
+        }
+    }
+
+    // `this` and `super`, which the compiler declares itself.
+    fn declare_hidden(&mut self,name: &str) {
+        let scope = self.scope();
+        let depth = scope.depth;
+        scope.locals.push(Local { name: name.to_string(), depth, initialized: true, declared: None });
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope().depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scope();
+        let depth = scope.depth;
+        scope.locals.retain(|l| l.depth < depth);
+        scope.depth -= 1;
+    }
+
+    // Finds a variable of an enclosing function, threading it through the
+    // upvalues of every function in between. None means it's a global.
+    fn resolve_upvalue(&mut self,f: usize,name: &str) -> Option<(u8,bool)> {
+        if f == 0 {
+            return None;
+        }
+        let (capture,initialized) = match self.functions[f - 1].resolve_local(name) {
+            Some((slot,initialized)) => (Capture { is_local: true, index: slot },initialized),
+            None => {
+                let (index,initialized) = self.resolve_upvalue(f - 1,name)?;
+                (Capture { is_local: false, index: index as u16 },initialized)
+            }
+        };
+        let captures = &mut self.functions[f].captures;
+        let i = match captures.iter().position(|c| *c == capture) {
+            Some(i) => i,
+            None => {
+                captures.push(capture);
+                captures.len() - 1
+            }
+        };
+        Some((i as u8,initialized))
+    }
+
+    // Records where `name` refers to under `t`, locals of the function being
+    // resolved shadow anything it could capture.
+    fn resolve_name(&mut self,t: &Token,name: &str,read: bool) {
+        let f = self.functions.len() - 1;
+        let (access,initialized) = match self.functions[f].resolve_local(name) {
+            Some((slot,initialized)) => (Access::Local(slot),initialized),
+            None => match self.resolve_upvalue(f,name) {
+                Some((index,initialized)) => (Access::Upvalue(index),initialized),
+                None => (Access::Global,true)
+            }
+        };
+        if read && !initialized {
+            self.error(format!("Can't read local variable '{}' in its own initializer",name),t);
+        }
+        if self.functions[f].captures.len() > u8::MAX as usize + 1 {
+            self.error("Too many closure variables in function".to_string(),t);
+        }
+        self.resolution.accesses.insert(t.span.start,access);
+    }
+
+    fn function(&mut self,key: &Token,params: &[Token],body: &Statement,kind: FunctionKind) {
+        self.functions.push(Scope::new(kind));
+        if kind == FunctionKind::Method || kind == FunctionKind::Initializer {
+            self.declare_hidden("this");
+        }
+        for p in params {
+            self.declare(p);
+            self.define();
+        }
+        self.statement(body);
+        let scope = self.functions.pop().unwrap();
+        self.resolution.captures.insert(key.span.start,scope.captures);
+    }
+
+    fn statements(&mut self,st: &[Rc<Statement>]) {
+        for s in st {
+            self.statement(s);
+        }
+        // dead code is allowed, e.g. after an early return put in for a while,
+        // the first statement that can't run is pointed out
+        let end = st.iter().position(|s| matches!(**s,Statement::Return(..) | Statement::Break(_) | Statement::Continue(_)));
+        if let Some(i) = end {
+            if let Some(next) = st.get(i + 1) {
+                let keyword = st[i].first_token().unwrap();
+                let t = next.first_token().unwrap_or(keyword);
+                self.warning(format!("Unreachable code after {}",keyword.lexeme),t);
+            }
+        }
+    }
+
+    fn statement(&mut self,st: &Statement) {
+        match st {
+            &Statement::Expression(ref e) | &Statement::Print(_,ref e) => self.expr(e),
+            &Statement::Variable(ref name,ref e) => {
+                self.declare(name);
+                if let Some(ref e) = *e {
+                    self.expr(e);
+                }
+                self.define();
+            },
+            &Statement::Block(_,ref st) => {
+                self.begin_scope();
+                self.statements(st);
+                self.end_scope();
+            },
+            &Statement::If(_,ref c,ref then,ref otherwise) => {
+                self.expr(c);
+                self.statement(then);
+                if let Some(ref s) = *otherwise {
+                    self.statement(s);
+                }
+            },
+            &Statement::While(_,ref c,ref body,ref increment) => {
+                self.expr(c);
+                self.scope().loops += 1;
+                self.statement(body);
+                self.scope().loops -= 1;
+                if let Some(ref i) = *increment {
+                    self.expr(i);
+                }
+            },
+            // a function's body isn't in the loops around the function
+            &Statement::Break(ref t) | &Statement::Continue(ref t) => {
+                if self.functions.last().unwrap().loops == 0 {
+                    self.error(format!("Can't use '{}' outside of a loop",t.lexeme),t);
+                }
+            },
+            // declared before the body, so a function can call itself
+            &Statement::Function(ref name,ref params,ref body) => {
+                self.declare(name);
+                self.define();
+                self.function(name,params,body,FunctionKind::Function);
+            },
+            &Statement::Class(ref name,ref superclass,ref methods) => {
+                self.declare(name);
+                self.define();
+                if let Some(ref s) = *superclass {
+                    self.expr(s);
+                    self.begin_scope();
+                    self.declare_hidden("super");
+                }
+                self.classes.push(if superclass.is_some() { ClassKind::Subclass } else { ClassKind::Class });
+                for m in methods {
+                    if let &Statement::Function(ref method,ref params,ref body) = &**m {
+                        let kind = if method.lexeme == "init" { FunctionKind::Initializer } else { FunctionKind::Method };
+                        self.function(method,params,body,kind);
+                    }
+                }
+                self.classes.pop();
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+            },
+            &Statement::Return(ref t,ref e) => {
+                match self.functions.last().unwrap().kind {
+                    FunctionKind::Script => self.error("Can't return from top-level code".to_string(),t),
+                    FunctionKind::Initializer if e.is_some() => self.error("Can't return a value from an initializer".to_string(),t),
+                    _ => ()
+                }
+                if let Some(ref e) = *e {
+                    self.expr(e);
+                }
+            },
+            &Statement::Import(..) => {},
+            &Statement::Export(_,ref declaration) => self.statement(declaration)
+        }
+    }
+
+    fn expr(&mut self,e: &Expr) {
+        match e {
+            &Expr::Literal(_) => {},
+            &Expr::Grouping(ref e) | &Expr::Unary(_,ref e) | &Expr::Get(ref e,_) => self.expr(e),
+            &Expr::Binary(ref a,_,ref b) | &Expr::Logical(ref a,_,ref b) | &Expr::Set(ref a,_,ref b) => {
+                self.expr(a);
+                self.expr(b);
+            },
+            &Expr::Variable(ref t) => self.resolve_name(t,&t.lexeme,true),
+            &Expr::Assign(ref t,ref e) => {
+                self.expr(e);
+                self.resolve_name(t,&t.lexeme,false);
+            },
+            &Expr::Call(ref callee,_,ref args) => {
+                self.expr(callee);
+                for a in args {
+                    self.expr(a);
+                }
+            },
+            &Expr::Function(ref keyword,ref params,ref body) => self.function(keyword,params,body,FunctionKind::Function),
+            &Expr::This(ref t) => {
+                if self.classes.is_empty() {
+                    self.error("Can't use 'this' outside of a class".to_string(),t);
+                } else {
+                    self.resolve_name(t,"this",true);
+                }
+            },
+            &Expr::Super(ref t,ref method) => {
+                match self.classes.last() {
+                    None => self.error("Can't use 'super' outside of a class".to_string(),t),
+                    Some(&ClassKind::Class) => self.error("Can't use 'super' in a class with no superclass".to_string(),t),
+                    Some(&ClassKind::Subclass) => {
+                        self.resolve_name(t,"super",true);
+                        self.resolve_name(method,"this",true);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::{TokenType,LoxError,Token,LoxType};
+use super::{TokenType,LoxError,Token,Span,LoxType};
 use std::collections::HashMap;
 use std::io;
 
@@ -14,6 +14,7 @@ lazy_static! {
                 m.insert("if",TokenType::If);
                 m.insert("nil",TokenType::Nil);
                 m.insert("or",TokenType::Or);
+                m.insert("print",TokenType::Print);
                 m.insert("return",TokenType::Return);
                 m.insert("super",TokenType::Super);
                 m.insert("this",TokenType::This);
@@ -21,16 +22,24 @@ lazy_static! {
                 m.insert("var",TokenType::Var);
                 m.insert("while",TokenType::While);
                 m.insert("import",TokenType::Import);
+                m.insert("export",TokenType::Export);
+                m.insert("break",TokenType::Break);
+                m.insert("continue",TokenType::Continue);
                 m
             };
 }
 
 pub struct Scanner {
     source: Vec<char>,
+    offsets: Vec<usize>, // byte offset of each char, plus one for the end
     pub tokens: Vec<Token>,
     current: i32,
     line: i32,
-    start: i32
+    line_start: i32,
+    start: i32,
+    start_line: i32,
+    start_column: i32,
+    unterminated: bool
 }
 
 macro_rules! add_match {
@@ -45,26 +54,53 @@ macro_rules! add_match {
 
 impl Scanner {
     pub fn new(s: String) -> Scanner {
+        let mut offsets: Vec<usize> = s.char_indices().map(|(i,_)| i).collect();
+        offsets.push(s.len());
         Scanner {
             source: s.chars().collect::<Vec<char>>(),
+            offsets,
             tokens: Vec::new(),
             current: 0,
-            line: 0,
-            start: 0
+            line: 1,
+            line_start: 0,
+            start: 0,
+            start_line: 1,
+            start_column: 1,
+            unterminated: false
         }
     }
 
+    // Whether the source stopped in the middle of something, like an unclosed
+    // '(' or '{' or an unterminated string, so more input could complete it.
+    pub fn is_incomplete(&self) -> bool {
+        let mut depth = 0;
+        for t in &self.tokens {
+            match t.token {
+                TokenType::LeftParenthesis | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParenthesis | TokenType::RightBrace => depth -= 1,
+                _ => ()
+            }
+        }
+        self.unterminated || depth > 0
+    }
+
     pub fn scan(&mut self) -> Result<(),Vec<LoxError>> {
         let mut errors: Vec<LoxError> = Vec::new();
         let mut failed = false;
         while !(self.is_end()) {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.current - self.line_start + 1;
             if let Err(errs)  = self.scan_token() {
                 errors.extend(errs);
                 failed = true;
             }
         }
-        self.tokens.push(Token { token: TokenType::EOF, lexeme:"".to_string(), line:self.line, literal: None });
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.current - self.line_start + 1;
+        let span = self.span();
+        self.tokens.push(Token { token: TokenType::EOF, lexeme:"".to_string(), span, literal: None });
 
         if failed {
             Err(errors)
@@ -75,7 +111,6 @@ impl Scanner {
 
     fn scan_token(&mut self) -> Result<(),Vec<LoxError>> {
         let mut errs: Vec<LoxError> = Vec::new();
-        let mut failed = false;
         let c = self.advance();
 //        println!("{}",&c);
         match c {
@@ -107,7 +142,7 @@ impl Scanner {
                     errs.push(e);
                 }
             },
-            '\n' => { self.line += 1; },
+            '\n' => self.newline(),
             ' ' => (),
             '\r' => (),
             '\t' => (),
@@ -115,23 +150,37 @@ impl Scanner {
                 if c.is_ascii_digit() {
                     if let Err(e) = self.number() {
                         errs.push(e);
-                        failed = true;
                     };
                 } else if c.is_alphabetic() || c == '_' || c == '-' {
                     self.identifier();
                 } else {
-                    errs.push(LoxError::new("Unexpected character".to_string(),self.line));
+                    errs.push(LoxError::new("Unexpected character".to_string(),self.span()));
                 }
             }
         };
 
-        if failed {
-            Err(errs)
-        } else {
+        if errs.is_empty() {
             Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: self.offsets[self.start as usize],
+            end: self.offsets[self.current as usize],
+            line: self.start_line,
+            column: self.start_column
         }
     }
 
+    // Called after consuming a '\n'.
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn advance(&mut self) -> char {
         self.current += 1;
         self.source[(self.current - 1) as usize]
@@ -139,13 +188,15 @@ impl Scanner {
 
     fn add(&mut self,t: TokenType) {
   //      println!("{:?}",t);
-        let lexeme = (&self.source[self.start as usize..self.current as usize]).iter().collect::<String>();
-        self.tokens.push(Token { token: t, lexeme: lexeme, line: self.line, literal: None });
+        let lexeme = self.source[self.start as usize..self.current as usize].iter().collect::<String>();
+        let span = self.span();
+        self.tokens.push(Token { token: t, lexeme, span, literal: None });
     }
 
     fn add_token(&mut self,t: TokenType,l: LoxType) {
-        let lexeme = (&self.source[self.start as usize..self.current as usize]).iter().collect::<String>();
-        self.tokens.push(Token { token: t, lexeme: lexeme, line: self.line, literal: Some(l) });
+        let lexeme = self.source[self.start as usize..self.current as usize].iter().collect::<String>();
+        let span = self.span();
+        self.tokens.push(Token { token: t, lexeme, span, literal: Some(l) });
     }
 
     fn match_c(&mut self, expected: char) -> bool {
@@ -178,18 +229,18 @@ impl Scanner {
 
     fn string(&mut self) -> Result<(),LoxError> {
         while self.peek() != '"' && !(self.is_end()) {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.newline();
             }
-            self.advance();
         }
 
         if self.is_end() {
-            return Err(LoxError::new("Unterminated string".to_string(),self.line));
+            self.unterminated = true;
+            return Err(LoxError::new("Unterminated string".to_string(),self.span()));
         }
 
         self.advance();
-        let s = (&self.source[(self.start + 1) as usize..(self.current - 1) as usize]).iter().collect::<String>();
+        let s = self.source[(self.start + 1) as usize..(self.current - 1) as usize].iter().collect::<String>();
         self.add_token(TokenType::String,LoxType::String(s));
         Ok(())
     }
@@ -208,7 +259,7 @@ impl Scanner {
         let s = &self.source[self.start as usize..self.current as usize].iter().collect::<String>();
         match s.parse::<f64>() {
             Ok(n) => self.add_token(TokenType::Number,LoxType::Number(n)),
-            Err(e) => return Err(LoxError::with_lower("Invalid number".to_string(),self.line,io::Error::new(io::ErrorKind::Other,e)))
+            Err(e) => return Err(LoxError::with_lower("Invalid number".to_string(),self.span(),io::Error::other(e)))
         };
         Ok(())
     }
@@ -217,7 +268,7 @@ impl Scanner {
         while self.peek().is_alphanumeric() || self.peek() == '_' || self.peek() == '-' {
             self.advance();
         }
-        let s = (&self.source[self.start as usize..self.current as usize]).iter().collect::<String>();
+        let s = self.source[self.start as usize..self.current as usize].iter().collect::<String>();
         match KEYWORDS.get(s.as_str()) {
             Some(t) => { self.add(t.clone()) },
             None => self.add(TokenType::Identifier)
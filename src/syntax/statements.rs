@@ -7,11 +7,31 @@ use std::fmt;
 pub enum Statement {
     Expression(Expr),
     Variable(Token,Option<Expr>),
-    Block(Vec<Rc<Statement>>),
-    If(Expr,Rc<Statement>,Option<Rc<Statement>>),
-    While(Expr,Rc<Statement>),
-    Return(Token,Expr),
-    Import(Token)
+    Block(Token,Vec<Rc<Statement>>), // `{`, or `for` around a for loop's variable
+    If(Token,Expr,Rc<Statement>,Option<Rc<Statement>>),
+    While(Token,Expr,Rc<Statement>,Option<Expr>), // `while` or `for`, condition, body, a for loop's increment
+    Break(Token),
+    Continue(Token),
+    Function(Token,Vec<Token>,Rc<Statement>),
+    Class(Token,Option<Expr>,Vec<Rc<Statement>>),
+    Return(Token,Option<Expr>), // None for a bare `return;`
+    Print(Token,Expr),
+    Import(Token,Option<Token>),
+    Export(Token,Rc<Statement>)
+}
+
+impl Statement {
+    // The first token of the statement there is, None for an expression
+    // statement made of literals only.
+    pub fn first_token(&self) -> Option<&Token> {
+        match self {
+            &Statement::Expression(ref e) => e.first_token(),
+            &Statement::Variable(ref t,_) | &Statement::Block(ref t,_) | &Statement::If(ref t,_,_,_)
+                | &Statement::While(ref t,_,_,_) | &Statement::Break(ref t) | &Statement::Continue(ref t)
+                | &Statement::Function(ref t,_,_) | &Statement::Class(ref t,_,_) | &Statement::Return(ref t,_)
+                | &Statement::Print(ref t,_) | &Statement::Import(ref t,_) | &Statement::Export(ref t,_) => Some(t)
+        }
+    }
 }
 
 impl fmt::Display for Statement {
@@ -19,14 +39,14 @@ impl fmt::Display for Statement {
         match self {
             &Statement::Expression(ref e) => { write!(f,"{};",e) },
             &Statement::Variable(ref t,ref e) => { write!(f,"var {} = {};",&t.lexeme,e.as_ref().unwrap()) },
-            &Statement::Block(ref l) => {
+            &Statement::Block(_,ref l) => {
                 writeln!(f,"{{")?;
                 for s in l {
                     writeln!(f,"{}",&s)?;
                 }
                 writeln!(f,"}}")
             },
-            &Statement::If(ref c,ref t,ref e) => {
+            &Statement::If(_,ref c,ref t,ref e) => {
                 writeln!(f,"if {}",c)?;
                 writeln!(f,"{}",t)?;
                 if let &Some(ref e_branch) = e {
@@ -35,13 +55,37 @@ impl fmt::Display for Statement {
                 }
                 Ok(())
             },
-            &Statement::While(ref c,ref b) => {
+            &Statement::While(_,ref c,ref b,ref i) => {
                 writeln!(f,"while {}",c)?;
-                writeln!(f,"{}",b)
+                writeln!(f,"{}",b)?;
+                if let &Some(ref i) = i {
+                    writeln!(f,"then {};",i)?;
+                }
+                Ok(())
+            },
+            &Statement::Break(_) => writeln!(f,"break;"),
+            &Statement::Continue(_) => writeln!(f,"continue;"),
+            &Statement::Function(ref t,_,_) => writeln!(f,"<fn {}>",t.lexeme),
+            &Statement::Class(ref t,ref s,ref methods) => {
+                match s {
+                    &Some(ref s) => writeln!(f,"class {} < {} {{",t.lexeme,s)?,
+                    &None => writeln!(f,"class {} {{",t.lexeme)?
+                }
+                for m in methods {
+                    writeln!(f,"{}",m)?;
+                }
+                writeln!(f,"}}")
+            },
+            &Statement::Return(_,ref e) => match e {
+                &Some(ref e) => writeln!(f,"return {};",e),
+                &None => writeln!(f,"return;")
+            },
+            &Statement::Print(_,ref e) => writeln!(f,"print {}",e),
+            &Statement::Import(ref t,ref alias) => match alias {
+                &Some(ref a) => writeln!(f,"import {} as {}",t.lexeme,a.lexeme),
+                &None => writeln!(f,"import {}",t.lexeme)
             },
-//            &Statement::Function(ref t,_,_) => writeln!(f,"<fn {}>",t.lexeme),
-            &Statement::Return(_,ref e) => writeln!(f,"return {}",e),
-            &Statement::Import(ref t) => writeln!(f,"import {}",t.lexeme)
+            &Statement::Export(_,ref s) => write!(f,"export {}",s)
         }
     }
 }
@@ -1,11 +1,19 @@
-use std::fmt;
 use super::{LoxType,TokenType};
 
+// Where something is in the source: a byte range plus the (1-based) line and
+// column it starts at.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: i32,
+    pub column: i32
+}
+
 #[derive(Debug,Clone)]
 pub struct Token {
     pub token: TokenType,
     pub lexeme: String,
-    pub line: i32,
+    pub span: Span,
     pub literal: Option<LoxType>
 }
-
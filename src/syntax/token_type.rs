@@ -39,5 +39,8 @@ pub enum TokenType {
     Var,
     While,
     Import,
+    Export,
+    Break,
+    Continue,
     EOF
 }
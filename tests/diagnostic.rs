@@ -0,0 +1,45 @@
+// Checks what diagnostics look like without colour, snippets and labels
+// included.
+use relax::diagnostic::{Diagnostic,Location};
+use relax::syntax::token::Span;
+
+const SOURCE: &str = "var a = 1;\n\tvar a = 2 +;\nprint a;\n";
+
+#[test]
+fn error_with_a_secondary_label() {
+    let d = Diagnostic::error("Already a variable named 'a'".to_string())
+        .at(Location::Span(Span { start: 16, end: 17, line: 2, column: 6 }))
+        .with_label(Span { start: 4, end: 5, line: 1, column: 5 },"first declared here".to_string())
+        .with_note("in script.lox".to_string());
+    let expected = "\
+error: Already a variable named 'a'
+ --> script.lox:2:6
+  |
+2 | \tvar a = 2 +;
+  | \t    ^
+1 | var a = 1;
+  |     - first declared here
+  = note: in script.lox
+";
+    assert_eq!(d.render(SOURCE,"script.lox",false),expected);
+}
+
+#[test]
+fn runtime_error_points_at_the_whole_line() {
+    let d = Diagnostic::error("Undefined variable 'b'".to_string()).at(Location::Line(3));
+    let expected = "\
+error: Undefined variable 'b'
+ --> script.lox:3
+  |
+3 | print a;
+  | ^^^^^^^^
+";
+    assert_eq!(d.render(SOURCE,"script.lox",false),expected);
+    assert_eq!(d.render_without_source("script.lox",false),"error: Undefined variable 'b'\n --> script.lox:3\n");
+}
+
+#[test]
+fn warnings_are_titled_as_such() {
+    let d = Diagnostic::warning("Unreachable code".to_string()).at(Location::Span(Span { start: 23, end: 28, line: 3, column: 1 }));
+    assert!(d.render(SOURCE,"script.lox",false).starts_with("warning: Unreachable code\n --> script.lox:3:1\n"));
+}
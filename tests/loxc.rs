@@ -0,0 +1,100 @@
+// Writes compiled programs to .loxc and reads them back, and checks broken
+// files are turned down with an error.
+#![allow(clippy::match_ref_pats,clippy::needless_borrowed_reference)]
+
+use relax::syntax::scanner::Scanner;
+use relax::syntax::parser::Parser;
+use relax::bytecode::compiler::{compile,Bytecode};
+use relax::bytecode::loxc;
+use relax::lox_type::LoxType;
+use relax::module::Loader;
+use std::io;
+
+fn bytecode(source: &str) -> Bytecode {
+    let mut scanner = Scanner::new(source.to_string());
+    if let Err(e) = scanner.scan() {
+        panic!("{:?}",e);
+    }
+    let (statements,errors) = Parser::new(scanner.tokens).parse();
+    assert!(errors.is_empty(),"{:?}",errors);
+    match compile(statements,Loader::default()) {
+        Ok(chunk) => chunk.to_bytecode(),
+        Err(e) => panic!("{:?}",e)
+    }
+}
+
+fn written(b: &Bytecode) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loxc::write(&mut bytes,b).unwrap();
+    bytes
+}
+
+// Functions only compare equal to themselves, so they're compared field by field.
+fn assert_same(a: &Bytecode,b: &Bytecode) {
+    assert_eq!(a.code,b.code);
+    assert_eq!(a.lines,b.lines);
+    assert_eq!(a.constants.len(),b.constants.len());
+    for (x,y) in a.constants.iter().zip(&b.constants) {
+        match (x,y) {
+            (&LoxType::Function(ref f),&LoxType::Function(ref g)) => {
+                assert_eq!(f.name,g.name);
+                assert_eq!(f.params,g.params);
+                assert_eq!(f.upvalue_count,g.upvalue_count);
+                assert_eq!(f.file,g.file);
+                assert_same(&f.bytecode,&g.bytecode);
+            },
+            _ => assert_eq!(x,y)
+        }
+    }
+}
+
+const PROGRAM: &str = "
+fun outer(a, b) {
+    var x = a;
+    fun inner() {
+        fun innermost() { return x + b; }
+        return innermost;
+    }
+    return inner;
+}
+var s = \"a string\";
+print outer(1.5, 2)()();
+print s != nil;";
+
+#[test]
+fn round_trip() {
+    let b = bytecode(PROGRAM);
+    let read = loxc::read(&mut written(&b).as_slice()).unwrap();
+    assert_same(&b,&read);
+    // the nested functions keep their own line tables
+    let outer = match b.constants.iter().find(|c| matches!(c,&&LoxType::Function(_))) {
+        Some(&LoxType::Function(ref f)) => f.clone(),
+        _ => panic!("no function constant")
+    };
+    assert!(outer.bytecode.lines.runs().len() > 1);
+}
+
+fn read_error(bytes: &[u8]) -> io::Error {
+    match loxc::read(&mut &bytes[..]) {
+        Ok(_) => panic!("read {} bytes of a broken file",bytes.len()),
+        Err(e) => e
+    }
+}
+
+#[test]
+fn rejects_broken_files() {
+    let bytes = written(&bytecode(PROGRAM));
+
+    let mut magic = bytes.clone();
+    magic[3] = b'X';
+    assert_eq!(read_error(&magic).to_string(),"not a .loxc file");
+
+    let mut version = bytes.clone();
+    version[loxc::MAGIC.len()] += 1;
+    assert_eq!(read_error(&version).to_string(),"unsupported .loxc version");
+
+    // cut anywhere, the file ends before what it says is still to come
+    for len in 0..bytes.len() {
+        read_error(&bytes[..len]);
+    }
+}
@@ -0,0 +1,31 @@
+// Checks what the parser makes of broken code: every error reported, and what
+// can be parsed around them kept.
+use relax::syntax::scanner::Scanner;
+use relax::syntax::parser::Parser;
+
+// The statements that parsed, printed back, and the lines of the errors.
+fn parse(source: &str) -> (Vec<String>,Vec<i32>) {
+    let mut scanner = Scanner::new(source.to_string());
+    if let Err(e) = scanner.scan() {
+        panic!("{:?}",e);
+    }
+    let (statements,errors) = Parser::new(scanner.tokens).parse();
+    (statements.iter().map(|s| s.to_string()).collect(),errors.iter().map(|e| e.span.line).collect())
+}
+
+#[test]
+fn recovers_at_the_next_statement() {
+    let (statements,errors) = parse("var a = ;\nprint 1 +;\nvar b = 2;");
+    assert_eq!(errors,vec![1,2]);
+    assert_eq!(statements,vec!["var b = 2;"]);
+}
+
+#[test]
+fn recovers_at_the_end_of_a_block() {
+    // the error is at the closing brace, which still ends the block
+    let (statements,errors) = parse("{\n  var a = 1;\n  print a +\n}\nvar b = 2;");
+    assert_eq!(errors,vec![4]);
+    assert_eq!(statements.len(),2);
+    assert!(statements[0].starts_with('{'),"{}",statements[0]);
+    assert_eq!(statements[1],"var b = 2;");
+}
@@ -0,0 +1,45 @@
+// Checks where the scanner says tokens are: byte ranges into the source, and
+// 1-based lines and columns counted in characters.
+use relax::syntax::scanner::Scanner;
+use relax::syntax::token::Span;
+
+fn spans(source: &str) -> Vec<(String,Span)> {
+    let mut scanner = Scanner::new(source.to_string());
+    if let Err(e) = scanner.scan() {
+        panic!("{:?}",e);
+    }
+    scanner.tokens.into_iter().filter(|t| !t.lexeme.is_empty()).map(|t| (t.lexeme,t.span)).collect()
+}
+
+fn span(start: usize,end: usize,line: i32,column: i32) -> Span {
+    Span { start, end, line, column }
+}
+
+#[test]
+fn spans_count_bytes_and_characters() {
+    let source = "var s = \"éü\";\n  // ñ\n  print s;";
+    let expected = vec![
+        ("var",span(0,3,1,1)),
+        ("s",span(4,5,1,5)),
+        ("=",span(6,7,1,7)),
+        // two bytes to each accented letter, one column
+        ("\"éü\"",span(8,14,1,9)),
+        (";",span(14,15,1,13)),
+        ("print",span(26,31,3,3)),
+        ("s",span(32,33,3,9)),
+        (";",span(33,34,3,10))
+    ];
+    let expected: Vec<(String,Span)> = expected.into_iter().map(|(l,s)| (l.to_string(),s)).collect();
+    assert_eq!(spans(source),expected);
+    for (lexeme,s) in spans(source) {
+        assert_eq!(&source[s.start..s.end],lexeme);
+    }
+}
+
+#[test]
+fn multi_line_strings_start_where_they_open() {
+    let source = "print \"a\nb\";\nprint 1;";
+    let tokens = spans(source);
+    assert_eq!(tokens[1],("\"a\nb\"".to_string(),span(6,11,1,7)));
+    assert_eq!(tokens[3],("print".to_string(),span(13,18,3,1)));
+}
@@ -0,0 +1,338 @@
+// Runs small programs through the compiler and the vm, checking what they print.
+use relax::syntax::scanner::Scanner;
+use relax::syntax::parser::Parser;
+use relax::syntax::statements::Statement;
+use relax::bytecode::compiler::{compile,Chunk};
+use relax::err::CompileError;
+use relax::bytecode::vm::Vm;
+use relax::module::Loader;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self,Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// Collects what print writes, the vm owns the writer so it's shared.
+#[derive(Clone,Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self,buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn parse(source: &str) -> Vec<Rc<Statement>> {
+    let mut scanner = Scanner::new(source.to_string());
+    if let Err(e) = scanner.scan() {
+        panic!("{:?}",e);
+    }
+    let (statements,errors) = Parser::new(scanner.tokens).parse();
+    assert!(errors.is_empty(),"{:?}",errors);
+    statements
+}
+
+fn messages(errors: Vec<CompileError>) -> Vec<String> {
+    errors.iter().map(|e| e.error.msg().to_string()).collect()
+}
+
+fn chunk(source: &str,loader: Loader) -> Result<Chunk,Vec<String>> {
+    compile(parse(source),loader).map_err(messages)
+}
+
+// What `source` prints, one line per print, or the message of the runtime
+// error that stopped it.
+fn interpret(source: &str,loader: Loader) -> Result<String,String> {
+    let chunk = match chunk(source,loader) {
+        Ok(chunk) => chunk,
+        Err(e) => panic!("{:?}",e)
+    };
+    let out = Output::default();
+    let mut vm = Vm::with_output(Box::new(out.clone()));
+    vm.load(&chunk.to_bytecode());
+    let res = vm.interpret();
+    let printed = String::from_utf8(out.0.borrow().clone()).unwrap();
+    res.map(|_| printed).map_err(|e| e.msg)
+}
+
+fn run(source: &str) -> String {
+    match interpret(source,Loader::default()) {
+        Ok(printed) => printed,
+        Err(e) => panic!("{}",e)
+    }
+}
+
+fn run_error(source: &str) -> String {
+    match interpret(source,Loader::default()) {
+        Ok(printed) => panic!("expected an error, printed {:?}",printed),
+        Err(e) => e
+    }
+}
+
+fn compile_errors(source: &str) -> Vec<String> {
+    match chunk(source,Loader::default()) {
+        Ok(_) => panic!("expected compile errors"),
+        Err(e) => e
+    }
+}
+
+// Writes `files` to a directory of their own, where `f`'s loader finds them.
+fn with_modules<T>(name: &str,files: &[(&str,&str)],f: impl FnOnce(Loader) -> T) -> T {
+    let dir = std::env::temp_dir().join(format!("relax-test-{}-{}",name,std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for &(file,source) in files {
+        fs::write(dir.join(file),source).unwrap();
+    }
+    let res = f(Loader::new(dir.clone(),Vec::new()));
+    fs::remove_dir_all(&dir).unwrap();
+    res
+}
+
+// Compiling deeply nested code recurses deeper than a test thread's stack goes.
+fn run_deep(source: &str) -> String {
+    let source = source.to_string();
+    let thread = std::thread::Builder::new().stack_size(256 * 1024 * 1024).spawn(move || run(&source));
+    thread.unwrap().join().unwrap()
+}
+
+fn repeat(code: &str,times: usize) -> String {
+    vec![code; times].join("\n")
+}
+
+#[test]
+fn long_else_branch() {
+    // the else branch needs a long jump over it, which the then jump goes over
+    let source = format!("var a = 0;\nif (false) {{ print 1; }} else {{\n{}\n}}\nprint a;",repeat("a = a + 1;",15000));
+    assert_eq!(run(&source),"15000\n");
+    let source = format!("var a = 0;\nif (true) {{ print 1; }} else {{\n{}\n}}\nprint a;",repeat("a = a + 1;",15000));
+    assert_eq!(run(&source),"1\n0\n");
+}
+
+#[test]
+fn long_then_branch() {
+    let source = format!("var a = 0;\nif (true) {{\n{}\n}} else {{ print 1; }}\nprint a;",repeat("a = a + 1;",15000));
+    assert_eq!(run(&source),"15000\n");
+}
+
+#[test]
+fn deeply_nested_blocks() {
+    // more scopes than a byte counts
+    let source = format!("{}print a;{}",repeat("{ var a = 0;",300),repeat("}",300));
+    assert_eq!(run_deep(&source),"0\n");
+}
+
+#[test]
+fn closures_share_captured_variables() {
+    let source = "
+        fun counter() {
+            var n = 0;
+            fun inc() { n = n + 1; return n; }
+            fun get() { return n; }
+            return fun () { inc(); return get(); };
+        }
+        var c = counter();
+        c();
+        print c();
+        print counter()();";
+    assert_eq!(run(source),"2\n1\n");
+}
+
+#[test]
+fn closures_are_on_the_function_line() {
+    let line = |source: &str,offset: usize| chunk(source,Loader::default()).unwrap().to_bytecode().lines.line_at(offset);
+    assert_eq!(line("\n\nfun f() {}",0),Some(3));
+    assert_eq!(line("var g =\n  fun () {};",0),Some(2));
+    // after Class, DefineGlobal and GetGlobal
+    assert_eq!(line("class A {\n\n  m() {}\n}",7),Some(3));
+}
+
+#[test]
+fn classes_and_initializers() {
+    let source = "
+        class A {
+            init(x) { this.x = x; if (x > 1) return; this.small = true; }
+            get() { return this.x; }
+        }
+        class B < A { get() { return super.get() + 1; } }
+        var a = A(1);
+        print a.small;
+        print a.init(5) == a;
+        print a.x;
+        print B(2).get();";
+    assert_eq!(run(source),"true\ntrue\n5\n3\n");
+    assert_eq!(compile_errors("class A { init() { return 1; } }"),vec!["Can't return a value from an initializer"]);
+}
+
+#[test]
+fn modules() {
+    let files = [("math.lox","fun double(x) { return x * 2; } var ten = 10; print \"loaded\";")];
+    let printed = with_modules("modules",&files,|loader| {
+        interpret("import \"math.lox\"; import \"math.lox\"; print double(ten);",loader)
+    });
+    // compiled and run once however often it's imported
+    assert_eq!(printed,Ok("loaded\n20\n".to_string()));
+}
+
+#[test]
+fn broken_modules_are_not_cached() {
+    let files = [("bad.lox","export var x = 1; print nope;")];
+    let errors = with_modules("broken",&files,|loader| {
+        // the repl compiles each input into the same chunk
+        let mut chunk = Chunk::with_loader(loader);
+        let first = chunk.compile_to_ops(parse("import \"bad.lox\";")).map_err(messages);
+        let second = chunk.compile_to_ops(parse("import \"bad.lox\";")).map_err(messages);
+        (first,second)
+    });
+    let expected = Err(vec!["Undefined variable 'nope'".to_string()]);
+    assert_eq!(errors,(expected.clone(),expected));
+}
+
+#[test]
+fn import_cycles() {
+    let files = [("a.lox","import \"b.lox\";"),("b.lox","import \"a.lox\";")];
+    let errors = with_modules("cycle",&files,|loader| chunk("import \"a.lox\";",loader).err().unwrap());
+    assert_eq!(errors.len(),1);
+    // the modules in the chain are named by their full paths
+    let chain: Vec<_> = errors[0].trim_start_matches("Import cycle: ").split(" -> ").map(|f| Path::new(f).file_name().unwrap()).collect();
+    assert_eq!(chain,["a.lox","b.lox","a.lox"]);
+}
+
+#[test]
+fn exports_and_aliases() {
+    let files = [("math.lox","var hidden = 1; export fun double(x) { return x * 2; } export var ten = 10;")];
+    let printed = with_modules("exports",&files,|loader| {
+        interpret("import \"math.lox\"; import \"math.lox\" as m; print double(ten); print m.double(1);",loader)
+    });
+    assert_eq!(printed,Ok("20\n2\n".to_string()));
+    let hidden = with_modules("hidden",&files,|loader| interpret("import \"math.lox\" as m; print m.hidden;",loader));
+    assert!(hidden.unwrap_err().contains("doesn't export 'hidden'"));
+}
+
+#[test]
+fn shadowing_and_scope_errors() {
+    assert_eq!(run("var a = 1; { var b = a + 1; { var a = b * 10; print a; } print b; } print a;"),"20\n2\n1\n");
+    assert_eq!(compile_errors("{ var a = a; }"),vec!["Can't read local variable 'a' in its own initializer"]);
+    assert_eq!(compile_errors("{ var a = 1; var a = 2; }"),vec!["Already a variable named 'a' in this scope"]);
+    // the same name in a nested scope shadows rather than clashes
+    assert_eq!(run("{ var a = 1; { var a = 2; } } fun f(a) { { var a = 3; } return a; } print f(4);"),"4\n");
+}
+
+#[test]
+fn undeclared_globals() {
+    assert_eq!(compile_errors("print nope;"),vec!["Undefined variable 'nope'"]);
+    assert_eq!(compile_errors("nope = 1;"),vec!["Undefined variable 'nope'"]);
+    // functions run later, so a global declared further down is fine in them
+    assert_eq!(run("fun f() { return later; } var later = 1; print f();"),"1\n");
+    assert_eq!(compile_errors("fun f() { return nope; }"),vec!["Undefined variable 'nope'"]);
+}
+
+#[test]
+fn many_locals() {
+    let declarations: Vec<String> = (0..300).map(|i| format!("var l{} = {};",i,i)).collect();
+    let source = format!("fun f() {{ {} return l0 + l255 + l299; }} print f();",declarations.join(" "));
+    assert_eq!(run(&source),"554\n");
+    let source = format!("{{ {} l299 = 1; print l299 + l256; }}",declarations.join(" "));
+    assert_eq!(run(&source),"257\n");
+}
+
+#[test]
+fn many_constants() {
+    let globals: Vec<String> = (0..400).map(|i| format!("var g{} = {};",i,i)).collect();
+    let source = format!("{} print g0 + g399; g300 = 1; print g300;",globals.join("\n"));
+    assert_eq!(run(&source),"399\n1\n");
+    let prints: Vec<String> = (0..300).map(|i| format!("print \"s{}\";",i)).collect();
+    let source = format!("fun f() {{ {} }} f();",prints.join(" "));
+    assert!(run(&source).ends_with("s298\ns299\n"));
+}
+
+#[test]
+fn and_or_short_circuit() {
+    let source = "
+        fun t(s) { print s; return true; }
+        fun f(s) { print s; return false; }
+        print f(\"a\") and t(\"b\");
+        print t(\"c\") or f(\"d\");
+        print f(\"e\") or t(\"f\");
+        print t(\"g\") and f(\"h\");
+        print nil or \"x\";
+        print 0 and 1;";
+    assert_eq!(run(source),"a\nfalse\nc\ntrue\ne\nf\ntrue\ng\nh\nfalse\nx\n1\n");
+}
+
+#[test]
+fn long_short_circuit() {
+    // the right operand is too long for a short jump over it
+    let source = format!("var a = 0;\nprint false and ({} 1);\nprint a;",repeat("(a = a + 1) +",8000));
+    assert_eq!(run_deep(&source),"false\n0\n");
+    let source = format!("var a = 0;\nprint true and ({} 1);\nprint a;",repeat("(a = a + 1) +",8000));
+    assert_eq!(run_deep(&source),"32004001\n8000\n");
+}
+
+#[test]
+fn type_errors() {
+    assert_eq!(run_error("print 1 - \"a\";"),"Operands of '-' must be numbers, not number and string");
+    assert_eq!(run_error("print 3 < \"a\";"),"Operands of '<' must be two numbers or two strings, not number and string");
+    assert_eq!(run_error("print true > false;"),"Operands of '>' must be two numbers or two strings, not boolean and boolean");
+    assert_eq!(run("print \"a\" < \"b\"; print 2 <= 2;"),"true\ntrue\n");
+}
+
+#[test]
+fn not_and_negate() {
+    assert_eq!(run("print !5; print !nil; print !!\"\"; print -(1 + 2); print --4;"),"false\ntrue\ntrue\n-3\n4\n");
+    assert_eq!(run_error("print -true;"),"Operand of '-' must be a number, not boolean");
+}
+
+#[test]
+fn continue_runs_the_increment() {
+    let source = "for (var i = 0; i < 5; i = i + 1) { if (i == 1 or i == 3) continue; print i; }";
+    assert_eq!(run(source),"0\n2\n4\n");
+    let source = "var i = 0; while (i < 3) { i = i + 1; if (i == 2) continue; print i; }";
+    assert_eq!(run(source),"1\n3\n");
+}
+
+#[test]
+fn break_leaves_the_innermost_loop() {
+    let source = "
+        for (var i = 0; i < 3; i = i + 1) {
+            for (var j = 0; j < 3; j = j + 1) {
+                if (j == 2) break;
+                print i * 10 + j;
+            }
+            if (i == 1) break;
+        }";
+    assert_eq!(run(source),"0\n1\n10\n11\n");
+}
+
+#[test]
+fn break_pops_captured_locals() {
+    let source = "
+        var fs = nil;
+        var after = \"after\";
+        for (var i = 0; i < 10; i = i + 1) {
+            var k = i;
+            { var doubled = k * 2; fun f() { return doubled; } fs = f; }
+            fun g() { return k; }
+            if (i == 3) { fs = g; break; }
+        }
+        print fs();
+        print after;";
+    assert_eq!(run(source),"3\nafter\n");
+    let source = "
+        var fs = nil;
+        while (true) { var a = 1; var b = 2; fun f() { return a + b; } fs = f; break; }
+        var c = 10;
+        print fs() + c;";
+    assert_eq!(run(source),"13\n");
+}
+
+#[test]
+fn break_and_continue_only_in_loops() {
+    assert_eq!(compile_errors("break;"),vec!["Can't use 'break' outside of a loop"]);
+    let source = "while (true) { fun f() { continue; } break; }";
+    assert_eq!(compile_errors(source),vec!["Can't use 'continue' outside of a loop"]);
+}